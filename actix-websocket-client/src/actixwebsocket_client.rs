@@ -59,7 +59,7 @@ impl Actor for ChatClient {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
-        // start heartbeats otherwise server will disconnect after 10 seconds
+        // start heartbeats otherwise server will disconnect after `CLIENT_TIMEOUT`
         self.hb(ctx)
     }
 
@@ -73,7 +73,7 @@ impl Actor for ChatClient {
 
 impl ChatClient {
     fn hb(&self, ctx: &mut Context<Self>) {
-        ctx.run_later(Duration::new(120, 0), |act, ctx| {
+        ctx.run_later(Duration::new(20, 0), |act, ctx| {
             act.0.write(Message::Ping(Bytes::from_static(b"")));
             act.hb(ctx);
 
@@ -9,14 +9,23 @@ mod tests {
             .await
             .expect("error connection");
 
-        let request = tonic::Request::new(activity::Message {
+        let mut request = tonic::Request::new(activity::Message {
             message: Some(activity::Activity {
                 activity_type: "event".to_string(),
                 content: "{\"subject\":\"Allen\",\"act\":\"love\",\"object\":\"rust\"}".to_string(),
+                content_type: activity::ContentType::Json as i32,
+                ..Default::default()
             }),
 
             receivers: vec!["gandum".to_string(), "00".to_string()],
+            ..Default::default()
         });
+        // veda登记了生产者时需要api key
+        if let Ok(api_key) = std::env::var("VEDA_API_KEY") {
+            request
+                .metadata_mut()
+                .insert("x-veda-api-key", api_key.parse().expect("invalid api key"));
+        }
 
         let response = client.active(request).await.expect("error request");
         println!("RESPONSE={:?}", response);
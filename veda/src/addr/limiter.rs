@@ -0,0 +1,224 @@
+use actix::prelude::*;
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::entity::Meister;
+
+/// 令牌桶,`rate`为每秒补充的令牌数,`burst`为桶容量
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            updated: now,
+        }
+    }
+
+    /// 补充从上次更新到`now`的令牌,返回当前令牌数
+    pub fn refill(&mut self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.updated = now;
+        self.tokens
+    }
+
+    /// 取走`n`个令牌,令牌数可以为负,补充回来之前不再放行
+    pub fn take(&mut self, n: f64) {
+        self.tokens -= n;
+    }
+
+    /// 桶已经满了,可以回收
+    pub fn is_full(&self) -> bool {
+        self.tokens >= self.burst
+    }
+}
+
+/// 限流规则,`rate`为0时不限流
+#[derive(Clone, Copy)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// 按生产者和接收者限流
+pub struct Limiter {
+    producer_limit: Limit,
+    receiver_limit: Limit,
+    producers: HashMap<String, TokenBucket>,
+    receivers: HashMap<Meister, TokenBucket>,
+}
+
+/// how often full buckets are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+impl Limiter {
+    pub fn new(producer_limit: Limit, receiver_limit: Limit) -> Self {
+        Self {
+            producer_limit,
+            receiver_limit,
+            producers: HashMap::new(),
+            receivers: HashMap::new(),
+        }
+    }
+}
+
+impl Actor for Limiter {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(SWEEP_INTERVAL, |act, _| {
+            let now = Instant::now();
+            act.producers.retain(|_, bucket| {
+                bucket.refill(now);
+                !bucket.is_full()
+            });
+            act.receivers.retain(|_, bucket| {
+                bucket.refill(now);
+                !bucket.is_full()
+            });
+        });
+    }
+}
+
+/// 生产者发送`messages`条消息,每个接收者收到`receivers`里对应的条数.
+/// 任何一个桶的令牌不足都拒绝整个请求,且不扣除任何令牌
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Throttle {
+    pub producer: String,
+    pub messages: usize,
+    pub receivers: HashMap<Meister, usize>,
+}
+
+impl Throttle {
+    pub fn new(
+        producer: String,
+        messages: usize,
+        receivers: impl Iterator<Item = Meister>,
+    ) -> Self {
+        let mut counts = HashMap::new();
+        for receiver in receivers {
+            *counts.entry(receiver).or_insert(0) += 1;
+        }
+        Self {
+            producer,
+            messages,
+            receivers: counts,
+        }
+    }
+}
+
+impl Handler<Throttle> for Limiter {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Throttle, _: &mut Self::Context) -> Self::Result {
+        let now = Instant::now();
+        let (producer_limit, receiver_limit) = (self.producer_limit, self.receiver_limit);
+
+        if producer_limit.rate > 0.0 {
+            let bucket = self
+                .producers
+                .entry(msg.producer.clone())
+                .or_insert_with(|| {
+                    TokenBucket::new(producer_limit.rate, producer_limit.burst, now)
+                });
+            // a big batch may borrow from the future as long as the bucket isn't empty
+            if bucket.refill(now) < 1.0 {
+                return Err(format!("producer `{}` is sending too fast", &msg.producer));
+            }
+        }
+
+        if receiver_limit.rate > 0.0 {
+            let limited: Vec<String> = msg
+                .receivers
+                .iter()
+                .filter(|(receiver, count)| {
+                    self.receivers
+                        .get_mut(*receiver)
+                        .map(|bucket| bucket.refill(now) < **count as f64)
+                        .unwrap_or(**count as f64 > receiver_limit.burst)
+                })
+                .map(|(receiver, _)| receiver.to_string())
+                .collect();
+            if !limited.is_empty() {
+                return Err(format!("receivers {:?} are receiving too fast", limited));
+            }
+
+            for (receiver, count) in &msg.receivers {
+                self.receivers
+                    .entry(receiver.clone())
+                    .or_insert_with(|| {
+                        TokenBucket::new(receiver_limit.rate, receiver_limit.burst, now)
+                    })
+                    .take(*count as f64);
+            }
+        }
+
+        if let Some(bucket) = self.producers.get_mut(&msg.producer) {
+            bucket.take(msg.messages as f64);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refill_a_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 4.0, start);
+        bucket.take(4.0);
+        assert_eq!(bucket.refill(start), 0.0);
+        assert_eq!(bucket.refill(start + Duration::from_secs(1)), 2.0);
+        assert_eq!(bucket.refill(start + Duration::from_secs(10)), 4.0);
+        assert!(bucket.is_full());
+    }
+
+    #[actix_rt::test]
+    async fn throttle_a_receiver() {
+        let unlimited = Limit {
+            rate: 0.0,
+            burst: 0.0,
+        };
+        let limit = Limit {
+            rate: 1.0,
+            burst: 2.0,
+        };
+        let limiter = Limiter::new(unlimited, limit).start();
+        let receivers = [Meister::new("", "gandum")];
+
+        for _ in 0..2 {
+            let res = limiter
+                .send(Throttle::new(
+                    "gn".to_string(),
+                    1,
+                    receivers.iter().cloned(),
+                ))
+                .await
+                .unwrap();
+            assert!(res.is_ok());
+        }
+        let res = limiter
+            .send(Throttle::new(
+                "gn".to_string(),
+                1,
+                receivers.iter().cloned(),
+            ))
+            .await
+            .unwrap();
+        assert!(res.is_err());
+    }
+}
@@ -1,20 +1,19 @@
+mod limiter;
+mod registry;
 mod rs;
 mod seravee;
+mod sse;
+mod webhook;
 mod ws;
 
-use actix::{Actor, Addr};
-use actix_web::web::{self, Data};
-use redis::Client;
+use actix::{Actor, Addr, Recipient};
 
-pub(crate) use self::{rs::*, seravee::*, ws::*};
+use std::sync::Arc;
 
-pub fn init_redis(redis_url: &str) -> Addr<Redis> {
-    let cli = Client::open(redis_url)
-        .expect(format!("unable to connect to redis:{}", redis_url).as_str());
-    Redis::new(cli).start()
-}
+use crate::storage::Storage;
+
+pub(crate) use self::{limiter::*, registry::*, rs::*, seravee::*, sse::*, webhook::*, ws::*};
 
-pub fn add_websocket(cfg: &mut web::ServiceConfig) {
-    let addr = Websocket::default().start();
-    cfg.app_data(Data::new(addr));
+pub fn init_redis(storage: Arc<dyn Storage>, webhook: Recipient<Callback>) -> Addr<Redis> {
+    Redis::new(storage, webhook).start()
 }
@@ -0,0 +1,500 @@
+use actix::prelude::*;
+use jsonschema::JSONSchema;
+use log::{info, warn};
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use validator::Validate;
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use super::Trial;
+use crate::{
+    constants::REGISTRY_RELOAD_INTERVAL,
+    entity::{validate_activity_type, validate_tenant, Activity, ContentType},
+    storage::Storage,
+};
+
+lazy_static! {
+    /// 模板里的变量,`{{ name }}`
+    static ref VARIABLE: Regex = Regex::new(r"\{\{\s*([A-Za-z0-9_.\-]+)\s*\}\}").unwrap();
+}
+
+/// 消息模板,渲染成`{"title": .., "body": ..}`的json正文
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Template {
+    pub title: String,
+    pub body: String,
+}
+
+/// 用变量替换模板里的`{{ name }}`,缺少变量时报错
+fn render(template: &str, variables: &HashMap<String, Value>) -> Result<String, String> {
+    let mut missing = None;
+    let rendered =
+        VARIABLE.replace_all(template, |caps: &Captures| match variables.get(&caps[1]) {
+            Some(Value::String(value)) => value.clone(),
+            Some(value) => value.to_string(),
+            None => {
+                missing.get_or_insert_with(|| caps[1].to_owned());
+                String::new()
+            }
+        });
+    match missing {
+        Some(name) => Err(format!("missing template variable `{}`", name)),
+        None => Ok(rendered.into_owned()),
+    }
+}
+
+/// 消息类型的登记信息
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ActivityKind {
+    /// admin接口从路径里取
+    #[serde(default)]
+    pub activity_type: String,
+    /// 正文需要满足的JSON Schema,为空时不校验
+    #[serde(default)]
+    pub schema: Option<Value>,
+    /// 消息在stream里保留的秒数,超时后不再推送,0为永久
+    #[serde(default)]
+    pub ttl: u64,
+    /// 同一批推送里优先级高的排在前面
+    #[serde(default)]
+    pub priority: i32,
+    /// 按语言登记的模板,默认模板的语言为空
+    #[serde(default)]
+    pub templates: HashMap<String, Template>,
+}
+
+impl ActivityKind {
+    /// `zh-CN`找不到时依次使用`zh`和默认模板
+    fn template(&self, locale: &str) -> Option<&Template> {
+        let language = locale.split(['-', '_']).next().unwrap_or_default();
+        self.templates
+            .get(locale)
+            .or_else(|| self.templates.get(language))
+            .or_else(|| self.templates.get(""))
+    }
+}
+
+/// 检查消息类型,编译它的JSON Schema
+fn compile(kind: ActivityKind) -> Result<(ActivityKind, Option<JSONSchema>), String> {
+    validate_activity_type(&kind.activity_type)
+        .map_err(|_| format!("invalid activity_type: {:?}", &kind.activity_type))?;
+    let schema = match &kind.schema {
+        Some(schema) => Some(
+            JSONSchema::compile(schema)
+                .map_err(|e| format!("invalid schema of `{}`: {}", &kind.activity_type, e))?,
+        ),
+        None => None,
+    };
+    Ok((kind, schema))
+}
+
+/// 消息类型注册表.配置文件里的类型是默认值,
+/// admin接口登记的类型保存在storage里,覆盖同名的默认值,每个实例定时重新加载
+pub struct Registry {
+    /// 配置文件里的消息类型
+    defaults: HashMap<String, (ActivityKind, Option<JSONSchema>)>,
+    /// admin接口登记的消息类型
+    registered: HashMap<String, (ActivityKind, Option<JSONSchema>)>,
+    storage: Arc<dyn Storage>,
+}
+
+impl Registry {
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        Self {
+            defaults: HashMap::new(),
+            registered: HashMap::new(),
+            storage,
+        }
+    }
+
+    /// 从json文件加载默认值,内容为`ActivityKind`数组
+    pub fn load(path: &str, storage: Arc<dyn Storage>) -> Result<Self, String> {
+        let file = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let kinds: Vec<ActivityKind> =
+            serde_json::from_str(&file).map_err(|e| format!("{}: {}", path, e))?;
+        let mut registry = Self::new(storage);
+        for kind in kinds {
+            let (kind, schema) = compile(kind)?;
+            registry
+                .defaults
+                .insert(kind.activity_type.clone(), (kind, schema));
+        }
+        info!(
+            "loaded {} activity types from {}",
+            registry.defaults.len(),
+            path
+        );
+        Ok(registry)
+    }
+
+    fn kind(&self, activity_type: &str) -> Option<&(ActivityKind, Option<JSONSchema>)> {
+        self.registered
+            .get(activity_type)
+            .or_else(|| self.defaults.get(activity_type))
+    }
+
+    /// 读取其它实例登记的消息类型,不能解析的记下日志后跳过
+    fn reload(&mut self) {
+        let kinds = match self.storage.activity_kinds() {
+            Ok(kinds) => kinds,
+            Err(e) => {
+                warn!("keep the registered activity types: {}", e);
+                return;
+            }
+        };
+        self.registered = kinds
+            .iter()
+            .filter_map(|kind| {
+                match serde_json::from_str(kind)
+                    .map_err(|e| e.to_string())
+                    .and_then(compile)
+                {
+                    Ok((kind, schema)) => Some((kind.activity_type.clone(), (kind, schema))),
+                    Err(e) => {
+                        warn!("skip a registered activity type: {}", e);
+                        None
+                    }
+                }
+            })
+            .collect();
+    }
+
+    fn register(&mut self, kind: ActivityKind) -> Result<(), String> {
+        let (kind, schema) = compile(kind)?;
+        let json = serde_json::to_string(&kind).map_err(|e| e.to_string())?;
+        self.storage
+            .register_kind(&kind.activity_type, &json)
+            .map_err(|e| e.to_string())?;
+        self.registered
+            .insert(kind.activity_type.clone(), (kind, schema));
+        Ok(())
+    }
+
+    /// 只能取消admin接口登记的,配置文件里的默认值仍然有效
+    fn unregister(&mut self, activity_type: &str) -> Result<bool, String> {
+        let existed = self
+            .storage
+            .unregister_kind(activity_type)
+            .map_err(|e| e.to_string())?;
+        self.registered.remove(activity_type);
+        Ok(existed)
+    }
+
+    /// 渲染模板,校验消息,并填上消息类型的过期时间和优先级
+    fn classify(&self, trial: &mut Trial) -> Result<(), String> {
+        let kind = self.kind(&trial.message.activity_type);
+        if let Some((kind, _)) = kind {
+            Self::render(kind, &mut trial.message)?;
+        }
+        trial.validate().map_err(|e| e.to_string())?;
+
+        match kind {
+            Some((kind, schema)) => Self::check(kind, schema.as_ref(), &mut trial.message),
+            None => Ok(()),
+        }
+    }
+
+    /// 广播没有接收者,只校验租户和消息
+    fn classify_broadcast(&self, tenant: &str, activity: &mut Activity) -> Result<(), String> {
+        let kind = self.kind(&activity.activity_type);
+        if let Some((kind, _)) = kind {
+            Self::render(kind, activity)?;
+        }
+        validate_tenant(tenant).map_err(|e| e.to_string())?;
+        activity.validate().map_err(|e| e.to_string())?;
+
+        match kind {
+            Some((kind, schema)) => Self::check(kind, schema.as_ref(), activity),
+            None => Ok(()),
+        }
+    }
+
+    /// 正文为空时使用模板生成
+    fn render(kind: &ActivityKind, activity: &mut Activity) -> Result<(), String> {
+        if !activity.activity.is_empty() {
+            return Ok(());
+        }
+        let template = match kind.template(&activity.locale) {
+            Some(template) => template,
+            None => return Ok(()),
+        };
+        let (title, body) = (
+            render(&template.title, &activity.variables)?,
+            render(&template.body, &activity.variables)?,
+        );
+        activity.activity = json!({ "title": title, "body": body }).to_string();
+        activity.content_type = ContentType::Json;
+        Ok(())
+    }
+
+    fn check(
+        kind: &ActivityKind,
+        schema: Option<&JSONSchema>,
+        activity: &mut Activity,
+    ) -> Result<(), String> {
+        if let Some(schema) = schema {
+            let content: Value = serde_json::from_str(&activity.activity)
+                .map_err(|_| format!("content of `{}` must be json", &kind.activity_type))?;
+            let errors: Vec<String> = match schema.validate(&content) {
+                Ok(()) => Vec::new(),
+                Err(errors) => errors.map(|e| e.to_string()).collect(),
+            };
+            if !errors.is_empty() {
+                return Err(format!(
+                    "content doesn't match the schema of `{}`: {}",
+                    &kind.activity_type,
+                    errors.join("; ")
+                ));
+            }
+        }
+
+        if kind.ttl > 0 {
+            activity.expire_at = Some(activity.created_at + kind.ttl as i64 * 1000);
+        }
+        activity.priority = kind.priority;
+        Ok(())
+    }
+}
+
+impl Actor for Registry {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.reload();
+        ctx.run_interval(REGISTRY_RELOAD_INTERVAL, |act, _| act.reload());
+    }
+}
+
+/// 按注册表校验每个`Trial`的消息,按顺序返回每个`Trial`的结果
+#[derive(Message)]
+#[rtype(result = "Vec<Result<Trial, String>>")]
+pub struct Classify {
+    pub trials: Vec<Trial>,
+}
+
+/// 按注册表校验广播的消息
+#[derive(Message)]
+#[rtype(result = "Result<Activity, String>")]
+pub struct ClassifyBroadcast {
+    pub tenant: String,
+    pub message: Activity,
+}
+
+/// 登记或更新消息类型
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct Register {
+    pub kind: ActivityKind,
+}
+
+/// 取消登记,返回是否存在
+#[derive(Message)]
+#[rtype(result = "Result<bool, String>")]
+pub struct Unregister {
+    pub activity_type: String,
+}
+
+/// 列出所有登记的消息类型
+#[derive(Message)]
+#[rtype(result = "Vec<ActivityKind>")]
+pub struct ListKinds;
+
+impl Handler<Classify> for Registry {
+    type Result = MessageResult<Classify>;
+
+    fn handle(&mut self, msg: Classify, _: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            msg.trials
+                .into_iter()
+                .map(|mut trial| self.classify(&mut trial).map(|_| trial))
+                .collect(),
+        )
+    }
+}
+
+impl Handler<ClassifyBroadcast> for Registry {
+    type Result = Result<Activity, String>;
+
+    fn handle(&mut self, mut msg: ClassifyBroadcast, _: &mut Self::Context) -> Self::Result {
+        self.classify_broadcast(&msg.tenant, &mut msg.message)?;
+        Ok(msg.message)
+    }
+}
+
+impl Handler<Register> for Registry {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Register, _: &mut Self::Context) -> Self::Result {
+        self.register(msg.kind)
+    }
+}
+
+impl Handler<Unregister> for Registry {
+    type Result = Result<bool, String>;
+
+    fn handle(&mut self, msg: Unregister, _: &mut Self::Context) -> Self::Result {
+        self.unregister(&msg.activity_type)
+    }
+}
+
+impl Handler<ListKinds> for Registry {
+    type Result = MessageResult<ListKinds>;
+
+    fn handle(&mut self, _: ListKinds, _: &mut Self::Context) -> Self::Result {
+        let mut kinds: Vec<ActivityKind> = self
+            .defaults
+            .keys()
+            .chain(self.registered.keys())
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .filter_map(|activity_type| self.kind(activity_type))
+            .map(|(kind, _)| kind.clone())
+            .collect();
+        kinds.sort_by(|a, b| a.activity_type.cmp(&b.activity_type));
+        MessageResult(kinds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn trial(activity_type: &str, activity: &str) -> Trial {
+        Trial {
+            tenant: String::new(),
+            message: Activity {
+                activity_type: activity_type.to_string(),
+                activity: activity.to_string(),
+                created_at: 1000,
+                ..Default::default()
+            },
+            receivers: vec!["gandum".to_string()],
+        }
+    }
+
+    #[test]
+    fn classify_an_activity() {
+        let mut registry = Registry::new(Arc::new(MemoryStorage::default()));
+        registry
+            .register(ActivityKind {
+                activity_type: "love".to_string(),
+                schema: Some(json!({
+                    "type": "object",
+                    "required": ["subject"],
+                    "properties": { "subject": { "type": "string" } }
+                })),
+                ttl: 60,
+                priority: 7,
+                templates: HashMap::new(),
+            })
+            .unwrap();
+
+        let mut love = trial("love", r#"{"subject":"Allen"}"#);
+        assert!(registry.classify(&mut love).is_ok());
+        assert_eq!(love.message.expire_at, Some(61000));
+        assert_eq!(love.message.priority, 7);
+
+        love.message.activity = r#"{"object":"rust"}"#.to_string();
+        assert!(registry.classify(&mut love).is_err());
+
+        assert!(registry.classify(&mut trial("hate", "anything")).is_ok());
+        assert!(registry.classify(&mut trial("hate", "")).is_err());
+
+        // a broadcast has no receivers but still follows the schema
+        let mut broadcast = trial("love", r#"{"object":"rust"}"#).message;
+        assert!(registry
+            .classify_broadcast("celestial", &mut broadcast)
+            .is_err());
+        broadcast.activity = r#"{"subject":"Allen"}"#.to_string();
+        assert!(registry
+            .classify_broadcast("celestial", &mut broadcast)
+            .is_ok());
+        assert_eq!(broadcast.priority, 7);
+    }
+
+    #[test]
+    fn render_a_template() {
+        let mut templates = HashMap::new();
+        templates.insert(
+            "".to_string(),
+            Template {
+                title: "Critical value".to_string(),
+                body: "{{ patient }}: {{value}}".to_string(),
+            },
+        );
+        templates.insert(
+            "zh".to_string(),
+            Template {
+                title: "危急值提醒".to_string(),
+                body: "{{ patient }}: {{value}}".to_string(),
+            },
+        );
+        let mut registry = Registry::new(Arc::new(MemoryStorage::default()));
+        registry
+            .register(ActivityKind {
+                activity_type: "WJZTX".to_string(),
+                schema: None,
+                ttl: 0,
+                priority: 0,
+                templates,
+            })
+            .unwrap();
+
+        let mut alert = trial("WJZTX", "");
+        alert.message.locale = "zh-CN".to_string();
+        alert
+            .message
+            .variables
+            .insert("patient".to_string(), json!("Allen"));
+        assert!(registry.classify(&mut alert).is_err());
+
+        alert
+            .message
+            .variables
+            .insert("value".to_string(), json!(7.2));
+        assert!(registry.classify(&mut alert).is_ok());
+        assert_eq!(alert.message.content_type, ContentType::Json);
+        assert_eq!(
+            serde_json::from_str::<Value>(&alert.message.activity).unwrap(),
+            json!({ "title": "危急值提醒", "body": "Allen: 7.2" })
+        );
+    }
+
+    #[test]
+    fn share_the_registered_kinds() {
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let path = std::env::temp_dir().join(format!("veda-kinds-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, r#"[{"activity_type":"love","priority":1}]"#).unwrap();
+        let mut ptolemaios = Registry::load(path.to_str().unwrap(), storage.clone()).unwrap();
+        let mut trinity = Registry::new(storage);
+        std::fs::remove_file(&path).unwrap();
+
+        ptolemaios
+            .register(ActivityKind {
+                activity_type: "love".to_string(),
+                schema: None,
+                ttl: 0,
+                priority: 7,
+                templates: HashMap::new(),
+            })
+            .unwrap();
+        // another instance picks it up once reloaded
+        trinity.reload();
+        let mut love = trial("love", "Allen");
+        trinity.classify(&mut love).unwrap();
+        assert_eq!(love.message.priority, 7);
+
+        // the default from the file is back after unregistering
+        assert_eq!(trinity.unregister("love"), Ok(true));
+        assert_eq!(ptolemaios.unregister("love"), Ok(false));
+        ptolemaios.reload();
+        ptolemaios.classify(&mut love).unwrap();
+        assert_eq!(love.message.priority, 1);
+    }
+}
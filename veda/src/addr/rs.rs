@@ -1,73 +1,187 @@
 use actix::{prelude::*, Recipient};
 
-use std::{collections::HashMap, usize};
-
-use log::info;
-use redis::streams::{StreamId, StreamInfoStreamReply, StreamReadOptions};
-use redis::{
-    streams::{StreamKey, StreamReadReply},
-    Client, Commands, Connection, RedisResult,
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
 };
 
-use super::WsMessage;
+use chrono::Utc;
+use futures::channel::oneshot;
+use log::{info, warn};
+use validator::Validate;
+
+use super::{Callback, WsMessage};
 
 use crate::{
-    constants::{BLOCK_MILLIS, MESSAGE_INTERVAL},
-    entity::{Activity, Platform},
+    config::CONFIG,
+    constants::{IDLE_POLL_INTERVAL, WAKE_RESUBSCRIBE},
+    entity::{
+        validate_receivers, validate_tenant, Activity, DeliveryEvent, DeliveryReceipt, Meister,
+        Platform, SessionId, Subscription,
+    },
+    storage::{Storage, StorageResult},
 };
 
+/// 消息队列和在线状态的actor,数据保存在`Storage`里
 pub struct Redis {
-    cli: Client,
-    sessions: HashMap<usize, Recipient<RedisOffline>>,
+    storage: Arc<dyn Storage>,
+    /// 在线的redis session,以及它的用户
+    sessions: HashMap<SessionId, (Meister, Addr<RedisSession>)>,
+    /// 用户的redis session,有新消息时唤醒
+    meisters: HashMap<Meister, HashSet<SessionId>>,
+    /// 等待新消息的长轮询请求,唤醒一次后移除
+    pollers: HashMap<Meister, Vec<oneshot::Sender<()>>>,
+    /// 消息回执
+    webhook: Recipient<Callback>,
 }
 
 impl Actor for Redis {
     type Context = Context<Self>;
+
+    /// 订阅其它实例的新消息通知,阻塞读取所以放在单独的线程里
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let (storage, addr) = (self.storage.clone(), ctx.address());
+        std::thread::spawn(move || loop {
+            match storage.subscribe_wake(&mut |meister| addr.do_send(Wake { meister })) {
+                Ok(()) => return,
+                Err(e) => {
+                    warn!("wake-up subscription is broken: {}", e);
+                    std::thread::sleep(WAKE_RESUBSCRIBE);
+                }
+            }
+        });
+        // drop the pollers whose requests are gone without being woken
+        ctx.run_interval(IDLE_POLL_INTERVAL, |act, _| {
+            act.pollers.retain(|_, pollers| {
+                pollers.retain(|poller| !poller.is_canceled());
+                !pollers.is_empty()
+            });
+        });
+    }
 }
 impl Redis {
-    pub fn new(cli: Client) -> Self {
+    pub fn new(storage: Arc<dyn Storage>, webhook: Recipient<Callback>) -> Self {
         Self {
-            cli,
+            storage,
             sessions: HashMap::with_capacity(1),
+            meisters: HashMap::new(),
+            pollers: HashMap::new(),
+            webhook,
         }
     }
-    /// 用户的设备hset
-    pub fn key_platform(&self, username: &str) -> String {
-        format!("platforms:{}", username)
+
+    /// 没有指定回调的消息使用按消息类型登记的webhook
+    fn resolve_callbacks(&self, trials: &mut [Trial]) {
+        let mut webhooks: HashMap<String, Option<String>> = HashMap::new();
+        for trial in trials
+            .iter_mut()
+            .filter(|trial| trial.message.callback.is_none())
+        {
+            let activity_type = &trial.message.activity_type;
+            let url = webhooks
+                .entry(activity_type.clone())
+                .or_insert_with(|| self.storage.webhook(activity_type).unwrap_or(None))
+                .clone();
+            trial.message.callback = url;
+        }
     }
-    /// 在线用户hset
-    pub fn hset_online_users(&self) -> &'static str {
-        "online-users"
+
+    /// 唤醒本实例里接收者的session,并通知其它实例
+    fn wake(&mut self, trials: &[Trial], receipts: &[Vec<Receipt>]) {
+        let meisters: Vec<Meister> = trials
+            .iter()
+            .zip(receipts)
+            .flat_map(|(trial, receipts)| {
+                receipts
+                    .iter()
+                    .filter(|receipt| receipt.queued.is_ok())
+                    .map(move |receipt| Meister::new(&trial.tenant, &receipt.receiver))
+            })
+            .collect();
+        for meister in &meisters {
+            self.wake_sessions(meister);
+        }
+        if let Err(e) = self.storage.publish_wake(&meisters) {
+            warn!("other instances are not woken up: {}", e);
+        }
+    }
+
+    fn wake_sessions(&mut self, meister: &Meister) {
+        for id in self.meisters.get(meister).into_iter().flatten() {
+            if let Some((_, addr)) = self.sessions.get(id) {
+                addr.do_send(Wake {
+                    meister: meister.clone(),
+                });
+            }
+        }
+        for poller in self.pollers.remove(meister).into_iter().flatten() {
+            let _ = poller.send(());
+        }
     }
-    /// 消息队列
-    pub fn key_activity(&self, username: &str) -> String {
-        format!("veda-activity:{}", username)
+}
+
+/// 给登记了回调的消息发送`Delivered`回执,并记下回调等待阅读和确认
+fn delivered(
+    storage: &dyn Storage,
+    webhook: &Recipient<Callback>,
+    meister: &Meister,
+    activities: &[Activity],
+) {
+    for activity in activities {
+        if let (Some(id), Some(url)) = (&activity.id, &activity.callback) {
+            let subscription = Subscription {
+                url: url.clone(),
+                activity_type: activity.activity_type.clone(),
+                message_id: activity.message_id.clone(),
+            };
+            if let Err(e) = storage.remember_callback(meister, id, &subscription) {
+                warn!("forget the callback of `{}`: {}", id, e);
+            }
+
+            let _ = webhook.do_send(Callback {
+                url: url.clone(),
+                receipt: DeliveryReceipt {
+                    message_id: activity.message_id.clone(),
+                    tenant: meister.tenant.clone(),
+                    receiver: meister.username.clone(),
+                    activity_type: activity.activity_type.clone(),
+                    event: DeliveryEvent::Delivered,
+                    at: Utc::now().timestamp(),
+                },
+            });
+        }
     }
 }
 
+/// 待推送的消息,优先级高的排在前面,以及已经过期的消息ID
+fn pending(activities: Vec<Activity>) -> (Vec<Activity>, Vec<String>) {
+    let now = Utc::now().timestamp_millis();
+    let (mut items, expired): (Vec<Activity>, Vec<Activity>) = activities
+        .into_iter()
+        .partition(|activity| !activity.is_expired(now));
+    items.sort_by_key(|activity| std::cmp::Reverse(activity.priority));
+    let expired = expired
+        .into_iter()
+        .filter_map(|activity| activity.id)
+        .collect();
+    (items, expired)
+}
+
 impl Handler<Online> for Redis {
     type Result = ();
 
     fn handle(&mut self, msg: Online, _ctx: &mut Self::Context) -> Self::Result {
-        info!("start creating redis connection for `{}`", &msg.name);
-
-        let mut con = self
-            .cli
-            .get_connection()
-            .expect("get redis connection error");
+        info!("start creating redis session for `{}`", &msg.meister);
 
-        let _: RedisResult<String> = con.hset(self.hset_online_users(), msg.id, msg.name.clone());
+        if let Err(e) = self.storage.online(msg.id, &msg.meister) {
+            warn!("session {} is not registered online: {}", msg.id, e);
+        }
 
-        let addr = RedisSession::new(
-            msg.id,
-            msg.name.clone(),
-            self.key_activity(&msg.name.as_str()),
-            con,
-            msg.addr,
-        )
-        .start();
+        let (id, meister) = (msg.id, msg.meister.clone());
+        let addr = RedisSession::new(self, msg).start();
 
-        self.sessions.insert(msg.id, addr.recipient());
+        self.meisters.entry(meister.clone()).or_default().insert(id);
+        self.sessions.insert(id, (meister, addr));
     }
 }
 
@@ -75,14 +189,9 @@ impl Handler<PlatformOnline> for Redis {
     type Result = ();
 
     fn handle(&mut self, msg: PlatformOnline, _ctx: &mut Self::Context) -> Self::Result {
-        info!("start creating redis connection for `{}`", &msg.name);
-
-        let mut con = self
-            .cli
-            .get_connection()
-            .expect("get redis connection error");
-
-        let _: RedisResult<Platform> = con.hset(self.key_platform(&msg.name), msg.id, msg.platform);
+        if let Err(e) = self.storage.platform(msg.id, &msg.meister, msg.platform) {
+            warn!("platform of session {} is not registered: {}", msg.id, e);
+        }
     }
 }
 
@@ -90,48 +199,132 @@ impl Handler<Offline> for Redis {
     type Result = ();
 
     fn handle(&mut self, msg: Offline, _: &mut Self::Context) -> Self::Result {
-        info!("name:{} disconnected, offline redis session", &msg.id);
-        if let Some(session_addr) = self.sessions.get(&msg.id) {
-            let _ = session_addr.do_send(RedisOffline);
-            self.sessions.remove(&msg.id);
-
-            let mut con = self
-                .cli
-                .get_connection()
-                .expect("get redis connection error");
-
-            let username: RedisResult<String> = con.hget(self.hset_online_users(), msg.id);
-            if let Ok(username) = username {
-                let _: RedisResult<String> = con.hdel(self.hset_online_users(), msg.id);
-                let key_platforms = self.key_platform(&username);
-                let _: RedisResult<Platform> = con.hdel(key_platforms, msg.id);
+        info!("session {} disconnected, offline redis session", &msg.id);
+        if let Some((meister, session_addr)) = self.sessions.remove(&msg.id) {
+            session_addr.do_send(RedisOffline);
+            if let Some(ids) = self.meisters.get_mut(&meister) {
+                ids.remove(&msg.id);
+                if ids.is_empty() {
+                    self.meisters.remove(&meister);
+                }
+            }
+            if let Err(e) = self.storage.offline(msg.id, &meister) {
+                warn!("session {} is not registered offline: {}", msg.id, e);
             }
-
-            let _: RedisResult<Platform> = con.hget(self.hset_online_users(), msg.id);
         }
     }
 }
 
 impl Handler<Trial> for Redis {
-    type Result = Vec<(String, String)>;
+    type Result = StorageResult<Vec<Receipt>>;
+
+    fn handle(&mut self, mut msg: Trial, _: &mut Self::Context) -> Self::Result {
+        self.resolve_callbacks(std::slice::from_mut(&mut msg));
+        let trials = std::slice::from_ref(&msg);
+        let mut receipts = self.storage.enqueue(trials)?;
+        self.wake(trials, &receipts);
+        Ok(receipts.pop().unwrap_or_default())
+    }
+}
 
-    fn handle(&mut self, msg: Trial, _: &mut Self::Context) -> Self::Result {
-        let mut con = self
-            .cli
-            .get_connection()
-            .expect("get redis connection error");
-        let mut events = vec![];
+impl Handler<Trials> for Redis {
+    type Result = StorageResult<Vec<Vec<Receipt>>>;
 
-        for receiv in &msg.receivers {
-            let id: RedisResult<String> =
-                con.xadd_map(self.key_activity(receiv), "*", &msg.message);
+    fn handle(&mut self, mut msg: Trials, _: &mut Self::Context) -> Self::Result {
+        self.resolve_callbacks(&mut msg.trials);
+        let receipts = self.storage.enqueue(&msg.trials)?;
+        self.wake(&msg.trials, &receipts);
+        Ok(receipts)
+    }
+}
+
+impl Handler<Subscribe> for Redis {
+    type Result = StorageResult<()>;
 
-            if let Ok(id) = id {
-                events.push((receiv.to_string(), id));
+    fn handle(&mut self, msg: Subscribe, _: &mut Self::Context) -> Self::Result {
+        self.storage
+            .subscribe(&msg.activity_type, msg.url.as_deref())
+    }
+}
+
+impl Handler<Acknowledge> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: Acknowledge, _: &mut Self::Context) -> Self::Result {
+        let subscription = match self.storage.callback(&msg.meister, &msg.id) {
+            Ok(subscription) => subscription,
+            Err(e) => {
+                warn!("drop {:?} receipt of `{}`: {}", msg.event, &msg.id, e);
+                return;
             }
+        };
+        if let Some(Subscription {
+            url,
+            activity_type,
+            message_id,
+        }) = subscription
+        {
+            let _ = self.webhook.do_send(Callback {
+                url,
+                receipt: DeliveryReceipt {
+                    message_id,
+                    tenant: msg.meister.tenant.clone(),
+                    receiver: msg.meister.username.clone(),
+                    activity_type,
+                    event: msg.event,
+                    at: Utc::now().timestamp(),
+                },
+            });
         }
 
-        events
+        // nothing more will be reported after an ack
+        if let DeliveryEvent::Acked = msg.event {
+            let _ = self.storage.forget_callback(&msg.meister, &msg.id);
+        }
+    }
+}
+
+/// 其它实例通知的新消息
+impl Handler<Wake> for Redis {
+    type Result = ();
+
+    fn handle(&mut self, msg: Wake, _: &mut Self::Context) -> Self::Result {
+        self.wake_sessions(&msg.meister);
+    }
+}
+
+impl Handler<Watch> for Redis {
+    type Result = MessageResult<Watch>;
+
+    fn handle(&mut self, msg: Watch, _: &mut Self::Context) -> Self::Result {
+        let (tx, rx) = oneshot::channel();
+        self.pollers.entry(msg.meister).or_default().push(tx);
+        MessageResult(rx)
+    }
+}
+
+impl Handler<Poll> for Redis {
+    type Result = StorageResult<Polled>;
+
+    fn handle(&mut self, msg: Poll, _: &mut Self::Context) -> Self::Result {
+        let cursor = msg.cursor.unwrap_or_else(|| "0".to_owned());
+
+        // the client has received everything up to the cursor
+        if cursor != "0" {
+            let (storage, webhook, meister) = (&*self.storage, &self.webhook, &msg.meister);
+            storage.ack_until(meister, &cursor, &mut |received| {
+                delivered(storage, webhook, meister, received)
+            })?;
+        }
+
+        let activities = self
+            .storage
+            .read_pending(&msg.meister, &cursor, msg.count)?;
+        // stream order, the cursor must be taken before sorting by priority
+        let cursor = activities.last().and_then(|activity| activity.id.clone());
+        let (activities, expired) = pending(activities);
+        self.storage.ack(&msg.meister, &expired)?;
+        Ok(Polled { activities, cursor })
     }
 }
 
@@ -139,19 +332,23 @@ impl Handler<Trial> for Redis {
 #[rtype(result = "()")]
 pub struct RedisOffline;
 pub struct RedisSession {
-    pub id: usize,
-    pub name: String,
-    stream_name: String,
-    opts: StreamReadOptions,
-    pub session_addr: Connection,
+    pub id: SessionId,
+    pub meister: Meister,
+    /// 客户端已经收到的最后一条消息的ID,之后的消息才会推送
+    cursor: String,
+    storage: Arc<dyn Storage>,
     pub websocket_addr: Recipient<WsMessage>,
+    webhook: Recipient<Callback>,
 }
 
 impl Actor for RedisSession {
     type Context = Context<Self>;
 
+    /// 有新消息时会被唤醒,定时读取只是防止丢失了通知
     fn started(&mut self, ctx: &mut Self::Context) {
-        ctx.run_interval(MESSAGE_INTERVAL, |act, ctx| {
+        self.trim_received();
+        self.read_messages(ctx);
+        ctx.run_interval(CONFIG.poll_interval(), |act, ctx| {
             act.read_messages(ctx);
         });
     }
@@ -165,72 +362,102 @@ impl Handler<RedisOffline> for RedisSession {
     }
 }
 
+impl Handler<Wake> for RedisSession {
+    type Result = ();
+
+    fn handle(&mut self, _: Wake, ctx: &mut Self::Context) -> Self::Result {
+        self.read_messages(ctx);
+    }
+}
+
 impl RedisSession {
-    pub fn new(
-        id: usize,
-        name: String,
-        stream_name: String,
-        connection: Connection,
-        websocket_addr: Recipient<WsMessage>,
-    ) -> Self {
+    pub fn new(redis: &Redis, online: Online) -> Self {
         Self {
-            id,
-            name,
-            stream_name,
-            opts: StreamReadOptions::default().block(BLOCK_MILLIS).count(10),
-            session_addr: connection,
-            websocket_addr,
+            id: online.id,
+            meister: online.meister,
+            cursor: online.cursor.unwrap_or_else(|| "0".to_owned()),
+            storage: redis.storage.clone(),
+            websocket_addr: online.addr,
+            webhook: redis.webhook.clone(),
         }
     }
-}
 
-impl RedisSession {
-    fn read_messages(&mut self, ctx: &mut Context<Self>) {
-        let inf: RedisResult<StreamInfoStreamReply> =
-            self.session_addr.xinfo_stream(&self.stream_name);
-        // if inf is Err(_), the xadd command have not been execute, no message
-        if let Ok(inf) = inf {
-            // no message in stream,keep pollings
-            if inf.length == 0 {
-                return;
-            }
+    /// 断线重连时,客户端已经收到的消息不再保留
+    fn trim_received(&mut self) {
+        if self.cursor == "0" {
+            return;
+        }
+        if let Err(e) = self
+            .storage
+            .ack_until(&self.meister, &self.cursor, &mut |_| {})
+        {
+            warn!("redis session {} keeps received activities: {}", self.id, e);
+        }
+    }
 
-            // read all messages in the stream
-            let ssr: RedisResult<StreamReadReply> =
-                self.session_addr
-                    .xread_options(&[&self.stream_name], &["0"], &self.opts);
-            if let Ok(ssr) = ssr {
-                for StreamKey { key, ids } in ssr.keys {
-                    let items: Vec<Activity> = ids
-                        .iter()
-                        .map(|t| Activity {
-                            activity_type: t.get("activity_type").unwrap_or_default(),
-                            activity: t.get("activity").unwrap_or_default(),
-                        })
-                        .collect();
-                    let res = serde_json::to_string(&items);
-                    if let Ok(res) = res {
-                        self.websocket_addr
-                            .send(WsMessage(res))
-                            .into_actor(self)
-                            .then(move |res, act, ctx| {
-                                match res {
-                                    Ok(_) => {
-                                        // remove all the sended messages out from stream
-                                        let id_strs: &Vec<&String> =
-                                            &ids.iter().map(|StreamId { id, map: _ }| id).collect();
-                                        let _: RedisResult<()> =
-                                            act.session_addr.xdel(key, id_strs);
-                                    }
-                                    // something wrong with socket server
-                                    _ => ctx.stop(),
-                                }
-                                fut::ready(())
-                            })
-                            .wait(ctx);
-                    }
+    fn read_messages(&mut self, ctx: &mut Context<Self>) {
+        // read all messages after the cursor
+        let activities =
+            match self
+                .storage
+                .read_pending(&self.meister, &self.cursor, CONFIG.batch_size)
+            {
+                Ok(activities) => activities,
+                Err(e) => {
+                    warn!("redis session {} read error: {}", self.id, e);
+                    return;
                 }
+            };
+        // no message in stream,keep pollings
+        let last_id = match activities.last().and_then(|activity| activity.id.clone()) {
+            Some(last_id) => last_id,
+            None => return,
+        };
+        let ids: Vec<String> = activities
+            .iter()
+            .filter_map(|activity| activity.id.clone())
+            .collect();
+        // there may be more, keep reading until the stream is drained
+        let drained = activities.len() < CONFIG.batch_size;
+        let (items, _) = pending(activities);
+
+        // nothing but expired activities, drop them without pushing
+        if items.is_empty() {
+            let _ = self.storage.ack(&self.meister, &ids);
+            self.cursor = last_id;
+            if !drained {
+                ctx.notify(Wake {
+                    meister: self.meister.clone(),
+                });
             }
+            return;
+        }
+        if let Ok(res) = serde_json::to_string(&items) {
+            self.websocket_addr
+                .send(WsMessage {
+                    msg: res,
+                    last_id: Some(last_id.clone()),
+                })
+                .into_actor(self)
+                .then(move |res, act, ctx| {
+                    match res {
+                        Ok(Ok(())) => {
+                            act.cursor = last_id;
+                            // remove all the sended messages out from stream
+                            let _ = act.storage.ack(&act.meister, &ids);
+                            delivered(&*act.storage, &act.webhook, &act.meister, &items);
+                            if !drained {
+                                ctx.notify(Wake {
+                                    meister: act.meister.clone(),
+                                });
+                            }
+                        }
+                        // the client is gone, keep the activities for the next session
+                        _ => ctx.stop(),
+                    }
+                    fut::ready(())
+                })
+                .wait(ctx);
         }
     }
 }
@@ -241,11 +468,13 @@ impl RedisSession {
 #[rtype(result = "()")]
 pub struct Online {
     /// websocket session id
-    pub id: usize,
-    /// logined username
-    pub name: String,
+    pub id: SessionId,
+    /// logined user
+    pub meister: Meister,
     /// `socket` session addr
     pub addr: Recipient<WsMessage>,
+    /// 客户端已经收到的最后一条消息的ID
+    pub cursor: Option<String>,
 }
 
 /// 用户上线消息,由websocket session发送到redis
@@ -254,24 +483,348 @@ pub struct Online {
 #[rtype(result = "()")]
 pub struct PlatformOnline {
     /// websocket session id
-    pub id: usize,
-    /// logined username
-    pub name: String,
+    pub id: SessionId,
+    /// logined user
+    pub meister: Meister,
     /// device
     pub platform: Platform,
 }
 
+/// 接收者有新消息,唤醒它的redis session立即读取
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Wake {
+    pub meister: Meister,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Offline {
     /// websocket session id
-    pub id: usize,
+    pub id: SessionId,
 }
 
 /// 审判
-#[derive(Message)]
-#[rtype(result = "Vec<(String, String)>")]
+#[derive(Clone, Message, Validate)]
+#[rtype(result = "StorageResult<Vec<Receipt>>")]
 pub struct Trial {
+    /// 接收者所在的租户
+    #[validate(custom = "validate_tenant")]
+    pub tenant: String,
+    #[validate]
     pub message: Activity,
+    #[validate(custom = "validate_receivers")]
     pub receivers: Vec<String>,
 }
+
+impl Trial {
+    /// 租户内的接收者
+    pub fn receivers(&self) -> impl Iterator<Item = Meister> + '_ {
+        self.receivers
+            .iter()
+            .map(move |receiver| Meister::new(&self.tenant, receiver))
+    }
+}
+
+/// 批量审判,每个`Trial`的结果按顺序返回
+#[derive(Message)]
+#[rtype(result = "StorageResult<Vec<Vec<Receipt>>>")]
+pub struct Trials {
+    pub trials: Vec<Trial>,
+}
+
+/// 按消息类型登记webhook,`url`为`None`时取消
+#[derive(Message)]
+#[rtype(result = "StorageResult<()>")]
+pub struct Subscribe {
+    pub activity_type: String,
+    pub url: Option<String>,
+}
+
+/// 客户端阅读或确认了消息,由websocket session发送到redis
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Acknowledge {
+    /// logined user
+    pub meister: Meister,
+    /// 消息在stream里的id
+    pub id: String,
+    pub event: DeliveryEvent,
+}
+
+/// 长轮询读取`cursor`之后的消息,`cursor`之前(含)的消息视为已推送
+#[derive(Message, Clone)]
+#[rtype(result = "StorageResult<Polled>")]
+pub struct Poll {
+    pub meister: Meister,
+    pub cursor: Option<String>,
+    /// 最多返回的消息数量
+    pub count: usize,
+}
+
+/// 长轮询等待用户的新消息,返回的channel在唤醒时完成
+#[derive(Message)]
+#[rtype(result = "oneshot::Receiver<()>")]
+pub struct Watch {
+    pub meister: Meister,
+}
+
+/// 长轮询的结果
+pub struct Polled {
+    /// 按优先级排序的消息
+    pub activities: Vec<Activity>,
+    /// 这一批里最大的消息ID,包括过期的,没有读到消息时为`None`
+    pub cursor: Option<String>,
+}
+
+/// 接收者的入队结果
+pub struct Receipt {
+    pub receiver: String,
+    /// 成功时为stream id,失败时为原因
+    pub queued: Result<String, String>,
+    /// 失败是暂时的(如连接断开),可以重试
+    pub transient: bool,
+    /// 接收者当前在线的session数量
+    pub devices: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{addr::Webhook, storage::MemoryStorage};
+    use futures::{
+        channel::mpsc::{unbounded, UnboundedSender},
+        StreamExt,
+    };
+    use std::time::Duration;
+
+    /// 把推送转发到channel
+    struct Probe(UnboundedSender<WsMessage>);
+
+    impl Actor for Probe {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WsMessage> for Probe {
+        type Result = Result<(), String>;
+
+        fn handle(&mut self, msg: WsMessage, _: &mut Self::Context) -> Self::Result {
+            let _ = self.0.unbounded_send(msg);
+            Ok(())
+        }
+    }
+
+    /// 客户端已经断开,推送总是失败
+    struct Gone;
+
+    impl Actor for Gone {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WsMessage> for Gone {
+        type Result = Result<(), String>;
+
+        fn handle(&mut self, _: WsMessage, _: &mut Self::Context) -> Self::Result {
+            Err("gone".to_owned())
+        }
+    }
+
+    fn mission(receiver: &str, activity: &str) -> Trial {
+        Trial {
+            tenant: "celestial".to_owned(),
+            message: Activity {
+                activity_type: "mission".to_owned(),
+                activity: activity.to_owned(),
+                ..Default::default()
+            },
+            receivers: vec![receiver.to_owned()],
+        }
+    }
+
+    fn redis() -> Addr<Redis> {
+        let webhook = Webhook::new(None).start();
+        Redis::new(Arc::new(MemoryStorage::default()), webhook.recipient()).start()
+    }
+
+    #[actix_rt::test]
+    async fn wake_a_session() {
+        let redis = redis();
+        let (tx, mut rx) = unbounded();
+        redis
+            .send(Online {
+                id: SessionId::new_v4(),
+                meister: Meister::new("celestial", "setsuna"),
+                addr: Probe(tx).start().recipient(),
+                cursor: None,
+            })
+            .await
+            .unwrap();
+
+        let receipts = redis
+            .send(mission("setsuna", "trans-am"))
+            .await
+            .unwrap()
+            .unwrap();
+        let pushed = actix_rt::time::timeout(Duration::from_millis(500), rx.next())
+            .await
+            .expect("the session is woken up at once")
+            .unwrap();
+        assert_eq!(pushed.last_id, receipts[0].queued.clone().ok());
+    }
+
+    #[actix_rt::test]
+    async fn keep_the_undelivered() {
+        let storage = Arc::new(MemoryStorage::default());
+        let webhook = Webhook::new(None).start();
+        let redis = Redis::new(storage.clone(), webhook.recipient()).start();
+        let setsuna = Meister::new("celestial", "setsuna");
+        redis
+            .send(Online {
+                id: SessionId::new_v4(),
+                meister: setsuna.clone(),
+                addr: Gone.start().recipient(),
+                cursor: None,
+            })
+            .await
+            .unwrap();
+
+        redis
+            .send(mission("setsuna", "trans-am"))
+            .await
+            .unwrap()
+            .unwrap();
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+        let pending = storage.read_pending(&setsuna, "0", 10).unwrap();
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn wake_a_poller() {
+        let redis = redis();
+        let woken = redis
+            .send(Watch {
+                meister: Meister::new("celestial", "lockon"),
+            })
+            .await
+            .unwrap();
+        // another user's activity doesn't wake the poller
+        let mut lockon = redis
+            .send(Watch {
+                meister: Meister::new("celestial", "lockon"),
+            })
+            .await
+            .unwrap();
+        redis
+            .send(mission("setsuna", "trans-am"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(lockon.try_recv(), Ok(None));
+
+        redis
+            .send(mission("lockon", "snipe"))
+            .await
+            .unwrap()
+            .unwrap();
+        actix_rt::time::timeout(Duration::from_millis(500), woken)
+            .await
+            .expect("the poller is woken up at once")
+            .unwrap();
+        assert_eq!(lockon.try_recv(), Ok(Some(())));
+    }
+
+    #[actix_rt::test]
+    async fn count_the_devices() {
+        let redis = redis();
+        let (tx, _rx) = unbounded();
+        let (phone, pad) = (SessionId::new_v4(), SessionId::new_v4());
+        for id in [phone, pad] {
+            redis
+                .send(Online {
+                    id,
+                    meister: Meister::new("celestial", "setsuna"),
+                    addr: Probe(tx.clone()).start().recipient(),
+                    cursor: None,
+                })
+                .await
+                .unwrap();
+        }
+
+        let mut trial = mission("setsuna", "trans-am");
+        trial.receivers.push("lockon".to_owned());
+        let devices = |receipts: Vec<Receipt>| -> Vec<usize> {
+            receipts.iter().map(|receipt| receipt.devices).collect()
+        };
+        let receipts = redis.send(trial.clone()).await.unwrap().unwrap();
+        assert_eq!(devices(receipts), [2, 0]);
+
+        redis.send(Offline { id: pad }).await.unwrap();
+        let receipts = redis.send(trial).await.unwrap().unwrap();
+        assert!(receipts.iter().all(|receipt| receipt.queued.is_ok()));
+        assert_eq!(devices(receipts), [1, 0]);
+    }
+
+    #[actix_rt::test]
+    async fn isolate_the_queues() {
+        let redis = redis();
+        redis
+            .send(mission("setsuna", "trans-am"))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let poll = |tenant: &str| {
+            redis.send(Poll {
+                meister: Meister::new(tenant, "setsuna"),
+                cursor: None,
+                count: 10,
+            })
+        };
+        assert_eq!(
+            poll("celestial").await.unwrap().unwrap().activities.len(),
+            1
+        );
+        assert!(poll("ptolemaios")
+            .await
+            .unwrap()
+            .unwrap()
+            .activities
+            .is_empty());
+        assert!(poll("").await.unwrap().unwrap().activities.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn acknowledge_by_cursor() {
+        let redis = redis();
+        let mut ids = Vec::new();
+        for (activity, priority) in [("trans-am", 0), ("exia", 9), ("dynames", 5)] {
+            let mut trial = mission("setsuna", activity);
+            trial.message.priority = priority;
+            let receipts = redis.send(trial).await.unwrap().unwrap();
+            ids.push(receipts[0].queued.clone().unwrap());
+        }
+
+        let poll = |cursor: Option<&String>| {
+            redis.send(Poll {
+                meister: Meister::new("celestial", "setsuna"),
+                cursor: cursor.cloned(),
+                count: 10,
+            })
+        };
+        let polled = poll(None).await.unwrap().unwrap();
+        let activities: Vec<&str> = polled
+            .activities
+            .iter()
+            .map(|activity| activity.activity.as_str())
+            .collect();
+        assert_eq!(activities, ["exia", "dynames", "trans-am"]);
+        // the cursor follows the stream, not the priority
+        assert_eq!(polled.cursor.as_ref(), ids.last());
+
+        // polling with the cursor acknowledges everything up to it
+        let polled = poll(polled.cursor.as_ref()).await.unwrap().unwrap();
+        assert!(polled.activities.is_empty());
+        assert_eq!(polled.cursor, None);
+        assert!(poll(None).await.unwrap().unwrap().activities.is_empty());
+    }
+}
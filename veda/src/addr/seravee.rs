@@ -1,27 +1,171 @@
-use std::net::SocketAddr;
+use std::convert::TryFrom;
 
-use actix::{Actor, Context, Recipient};
+use actix::{Actor, Addr, Context, MailboxError};
 use chrono::Utc;
-use tonic::Code;
+use serde_json::{json, Value};
 
-use super::Trial;
+use super::{
+    Classify, Kick, Limiter, Receipt, Redis, Registry, Subscribe, Throttle, Trial, Trials,
+    Websocket,
+};
 use crate::{
     activity::{self, activity_source_server::ActivitySource},
-    entity::Activity,
+    auth::Producer,
+    constants::TRIAL_TIMEOUT,
+    entity::{Activity, ContentType},
+    storage::StorageError,
 };
 
-impl Into<Activity> for activity::Activity {
-    fn into(self) -> Activity {
-        Activity {
-            activity_type: self.activity_type,
-            activity: self.content,
+impl TryFrom<activity::Activity> for Activity {
+    type Error = String;
+
+    fn try_from(msg: activity::Activity) -> Result<Self, Self::Error> {
+        let content_type = match activity::ContentType::from_i32(msg.content_type) {
+            Some(activity::ContentType::Text) => ContentType::Text,
+            Some(activity::ContentType::Json) => ContentType::Json,
+            Some(activity::ContentType::Html) => ContentType::Html,
+            Some(activity::ContentType::Protobuf) => ContentType::Protobuf,
+            None => return Err(format!("unknown content_type: {}", msg.content_type)),
+        };
+        let content = match content_type {
+            ContentType::Protobuf => {
+                let any = msg.any.ok_or("any is required for protobuf content")?;
+                json!({ "@type": any.type_url, "value": base64::encode(any.value) }).to_string()
+            }
+            _ => msg.content,
+        };
+        Ok(Activity {
+            activity_type: msg.activity_type,
+            content_type,
+            activity: content,
+            headers: msg.headers,
+            variables: msg
+                .variables
+                .into_iter()
+                .map(|(name, value)| (name, Value::String(value)))
+                .collect(),
+            locale: msg.locale,
+            ..Default::default()
+        })
+    }
+}
+/// grpc消息转换为`Trial`,由`Registry`渲染和校验,错误信息用于`InvalidArgument`
+fn trial(msg: activity::Message, producer: &str) -> Result<Trial, String> {
+    let content = msg.message.ok_or("message is required")?;
+    let mut message = Activity::try_from(content)?;
+    message.stamp(producer);
+    message.callback = Some(msg.callback).filter(|url| !url.is_empty());
+    Ok(Trial {
+        tenant: msg.tenant,
+        message,
+        receivers: msg.receivers,
+    })
+}
+
+/// 认证过的生产者,没有登记生产者时为空
+fn authenticated<T>(request: &tonic::Request<T>) -> Option<Producer> {
+    request.extensions().get::<Producer>().cloned()
+}
+
+/// 生产者身份,认证过时使用登记的名字,否则使用对端地址.
+/// 客户端自己声明的名字不能作为限流的依据
+fn producer<T>(request: &tonic::Request<T>) -> String {
+    if let Some(producer) = request.extensions().get::<Producer>() {
+        return producer.name.clone();
+    }
+    request
+        .remote_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default()
+}
+
+/// 检查认证过的生产者能不能发送这条消息
+fn authorize(producer: Option<&Producer>, trial: &Trial) -> Result<(), String> {
+    match producer {
+        Some(producer) => producer.authorize(trial),
+        None => Ok(()),
+    }
+}
+
+/// actor没能及时处理消息
+fn mailbox_status(e: MailboxError) -> tonic::Status {
+    match e {
+        // the actor's mailbox is too busy to take the message in time
+        MailboxError::Timeout => {
+            tonic::Status::resource_exhausted("veda is overloaded, try again later")
         }
+        MailboxError::Closed => tonic::Status::unavailable("veda is shutting down"),
     }
 }
+
+/// 存储不可用
+fn storage_status(e: StorageError) -> tonic::Status {
+    tonic::Status::unavailable(format!("storage unavailable: {}", e))
+}
+
+/// 每个接收者的入队结果转换为grpc返回
+fn states(receipts: Vec<Receipt>) -> activity::States {
+    activity::States {
+        states: receipts
+            .into_iter()
+            .map(|receipt| {
+                let (outcome, message, reason) = match receipt.queued {
+                    Ok(id) => (activity::Outcome::Queued, id, String::new()),
+                    Err(reason) => (activity::Outcome::Failed, String::new(), reason),
+                };
+                activity::Status {
+                    message,
+                    receiver: receipt.receiver,
+                    action: 0,
+                    expire_at: Utc::now().timestamp(),
+                    outcome: outcome as i32,
+                    reason,
+                    online: receipt.devices > 0,
+                    devices: receipt.devices as i64,
+                }
+            })
+            .collect(),
+    }
+}
+
+/// 批量发送时不能入队的消息,每个接收者都是`Failed`
+fn rejected(receivers: &[String], reason: String) -> activity::States {
+    let receivers = match receivers.is_empty() {
+        true => vec![String::new()],
+        false => receivers.to_vec(),
+    };
+    states(
+        receivers
+            .into_iter()
+            .map(|receiver| Receipt {
+                receiver,
+                queued: Err(reason.clone()),
+                transient: false,
+                devices: 0,
+            })
+            .collect(),
+    )
+}
+
 #[derive(Clone)]
 pub struct Seravee {
-    pub addr: SocketAddr,
-    pub redis_addr: Recipient<Trial>,
+    pub redis_addr: Addr<Redis>,
+    pub limiter_addr: Addr<Limiter>,
+    pub registry_addr: Addr<Registry>,
+    pub websocket_addr: Addr<Websocket>,
+}
+
+impl Seravee {
+    /// 按消息类型注册表校验,按顺序返回每条消息的结果
+    async fn classify(
+        &self,
+        trials: Vec<Trial>,
+    ) -> Result<Vec<Result<Trial, String>>, tonic::Status> {
+        self.registry_addr
+            .send(Classify { trials })
+            .await
+            .map_err(mailbox_status)
+    }
 }
 
 impl Actor for Seravee {
@@ -34,33 +178,165 @@ impl ActivitySource for Seravee {
         &self,
         request: tonic::Request<activity::Message>,
     ) -> Result<tonic::Response<activity::States>, tonic::Status> {
-        let msg = request.into_inner();
-        let content = msg.message.unwrap();
-        let trail = Trial {
-            message: content.into(),
-            receivers: msg.receivers,
-        };
+        let (authenticated, producer) = (authenticated(&request), producer(&request));
+        let trail =
+            trial(request.into_inner(), &producer).map_err(tonic::Status::invalid_argument)?;
+        authorize(authenticated.as_ref(), &trail).map_err(tonic::Status::permission_denied)?;
+        let trail = self
+            .classify(vec![trail])
+            .await?
+            .pop()
+            .expect("classify keeps every trial")
+            .map_err(tonic::Status::invalid_argument)?;
+
+        self.limiter_addr
+            .send(Throttle::new(producer, 1, trail.receivers()))
+            .await
+            .map_err(mailbox_status)?
+            .map_err(tonic::Status::resource_exhausted)?;
+
+        let receipts = self
+            .redis_addr
+            .send(trail)
+            .timeout(TRIAL_TIMEOUT)
+            .await
+            .map_err(mailbox_status)?
+            .map_err(storage_status)?;
+        Ok(tonic::Response::new(states(receipts)))
+    }
+
+    async fn active_batch(
+        &self,
+        request: tonic::Request<activity::Messages>,
+    ) -> Result<tonic::Response<activity::Batch>, tonic::Status> {
+        let (authenticated, producer) = (authenticated(&request), producer(&request));
+        let messages = request.into_inner().messages;
+        // 不合法或者没有权限的消息直接报告失败,其余的照常入队
+        let mut results: Vec<Option<activity::States>> = vec![None; messages.len()];
 
-        let ids = &self.redis_addr.send(trail).await;
-        match ids {
-            Ok(ids) => {
-                let msgs: Vec<activity::Status> = ids
-                    .into_iter()
-                    .map(|str| activity::Status {
-                        message: str.to_owned().1,
-                        receiver: str.to_owned().0,
-                        action: 0,
-                        expire_at: Utc::now().timestamp(),
-                    })
-                    .collect();
-
-                let resp = activity::States { states: msgs };
-
-                let response: tonic::Response<activity::States> = tonic::Response::new(resp);
-                Ok(response)
+        let (mut accepted, mut trials) = (Vec::new(), Vec::new());
+        for (i, msg) in messages.into_iter().enumerate() {
+            let receivers = msg.receivers.clone();
+            match trial(msg, &producer).and_then(|trial| {
+                authorize(authenticated.as_ref(), &trial)?;
+                Ok(trial)
+            }) {
+                Ok(trial) => {
+                    accepted.push((i, receivers));
+                    trials.push(trial);
+                }
+                Err(e) => results[i] = Some(rejected(&receivers, e)),
             }
-            Err(e) => Err(tonic::Status::new(Code::InvalidArgument, e.to_string())),
         }
+
+        let classified = self.classify(trials).await?;
+        let (mut indexes, mut trials) = (Vec::new(), Vec::new());
+        for ((i, receivers), trial) in accepted.into_iter().zip(classified) {
+            match trial {
+                Ok(trial) => {
+                    indexes.push(i);
+                    trials.push(trial);
+                }
+                Err(e) => results[i] = Some(rejected(&receivers, e)),
+            }
+        }
+
+        if !trials.is_empty() {
+            self.limiter_addr
+                .send(Throttle::new(
+                    producer,
+                    trials.len(),
+                    trials.iter().flat_map(Trial::receivers),
+                ))
+                .await
+                .map_err(mailbox_status)?
+                .map_err(tonic::Status::resource_exhausted)?;
+
+            let queued = self
+                .redis_addr
+                .send(Trials { trials })
+                .timeout(TRIAL_TIMEOUT)
+                .await
+                .map_err(mailbox_status)?
+                .map_err(storage_status)?;
+            for (i, receipts) in indexes.into_iter().zip(queued) {
+                results[i] = Some(states(receipts));
+            }
+        }
+
+        Ok(tonic::Response::new(activity::Batch {
+            results: results
+                .into_iter()
+                .map(|states| states.expect("every message has a result"))
+                .collect(),
+        }))
+    }
+
+    async fn subscribe(
+        &self,
+        request: tonic::Request<activity::Webhook>,
+    ) -> Result<tonic::Response<activity::Webhook>, tonic::Status> {
+        let producer = authenticated(&request).ok_or_else(|| {
+            tonic::Status::permission_denied("subscribe requires an authenticated producer")
+        })?;
+        let webhook = request.into_inner();
+        if webhook.activity_type.is_empty() {
+            return Err(tonic::Status::invalid_argument("activity_type is required"));
+        }
+        if !producer.allows_activity_type(&webhook.activity_type) {
+            return Err(tonic::Status::permission_denied(format!(
+                "producer `{}` can't subscribe `{}`",
+                &producer.name, &webhook.activity_type
+            )));
+        }
+        if !webhook.url.is_empty() && !validator::validate_url(&webhook.url) {
+            return Err(tonic::Status::invalid_argument("url is invalid"));
+        }
+
+        self.redis_addr
+            .send(Subscribe {
+                activity_type: webhook.activity_type.clone(),
+                url: Some(webhook.url.clone()).filter(|url| !url.is_empty()),
+            })
+            .timeout(TRIAL_TIMEOUT)
+            .await
+            .map_err(mailbox_status)?
+            .map_err(storage_status)?;
+        Ok(tonic::Response::new(webhook))
+    }
+
+    async fn kick(
+        &self,
+        request: tonic::Request<activity::KickRequest>,
+    ) -> Result<tonic::Response<activity::KickReply>, tonic::Status> {
+        let producer = authenticated(&request).ok_or_else(|| {
+            tonic::Status::permission_denied("kick requires an authenticated admin producer")
+        })?;
+        let kick = request.into_inner();
+        if !producer.admin || !producer.allows_tenant(&kick.tenant) {
+            return Err(tonic::Status::permission_denied(format!(
+                "producer `{}` can't kick sessions of tenant `{}`",
+                &producer.name, &kick.tenant
+            )));
+        }
+        let kick = Kick::new(
+            &kick.session,
+            &kick.tenant,
+            &kick.username,
+            &kick.platform,
+            &kick.reason,
+        )
+        .map_err(tonic::Status::invalid_argument)?;
+
+        let sessions = self
+            .websocket_addr
+            .send(kick)
+            .timeout(TRIAL_TIMEOUT)
+            .await
+            .map_err(mailbox_status)?;
+        Ok(tonic::Response::new(activity::KickReply {
+            sessions: sessions as i64,
+        }))
     }
 
     async fn act_flow(
@@ -69,6 +345,126 @@ impl ActivitySource for Seravee {
     ) -> Result<tonic::Response<activity::Status>, tonic::Status> {
         todo!()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        addr::{Limit, Poll, Webhook},
+        entity::Meister,
+        storage::MemoryStorage,
+    };
+    use std::sync::Arc;
+
+    fn seravee() -> Seravee {
+        let webhook = Webhook::new(None).start();
+        let storage = Arc::new(MemoryStorage::default());
+        let limit = Limit {
+            rate: 1000.0,
+            burst: 1000.0,
+        };
+        Seravee {
+            redis_addr: Redis::new(storage.clone(), webhook.recipient()).start(),
+            limiter_addr: Limiter::new(limit, limit).start(),
+            registry_addr: Registry::new(storage).start(),
+            websocket_addr: Websocket::default().start(),
+        }
+    }
 
-  
+    fn message(receivers: &[&str], content: &str) -> activity::Message {
+        activity::Message {
+            receivers: receivers
+                .iter()
+                .map(|receiver| receiver.to_string())
+                .collect(),
+            message: Some(activity::Activity {
+                activity_type: "mission".to_owned(),
+                content: content.to_owned(),
+                ..Default::default()
+            }),
+            tenant: "celestial".to_owned(),
+            ..Default::default()
+        }
+    }
+
+    #[actix_rt::test]
+    async fn queue_a_batch() {
+        let seravee = seravee();
+        let mut messages: Vec<_> = (0..5)
+            .map(|i| message(&["setsuna", "lockon"], &format!("mission {}", i)))
+            .collect();
+        messages[2].message = None;
+        let batch = seravee
+            .active_batch(tonic::Request::new(activity::Messages { messages }))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(batch.results.len(), 5);
+        let outcomes: Vec<Vec<i32>> = batch
+            .results
+            .iter()
+            .map(|states| states.states.iter().map(|state| state.outcome).collect())
+            .collect();
+        let (queued, failed) = (
+            activity::Outcome::Queued as i32,
+            activity::Outcome::Failed as i32,
+        );
+        assert_eq!(outcomes[0], [queued, queued]);
+        // a bad message fails alone, the rest are queued in order
+        assert_eq!(outcomes[2], [failed, failed]);
+        assert_eq!(batch.results[2].states[0].reason, "message is required");
+        assert_eq!(outcomes[4], [queued, queued]);
+
+        let polled = seravee
+            .redis_addr
+            .send(Poll {
+                meister: Meister::new("celestial", "lockon"),
+                cursor: None,
+                count: 10,
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        let contents: Vec<&str> = polled
+            .activities
+            .iter()
+            .map(|activity| activity.activity.as_str())
+            .collect();
+        assert_eq!(
+            contents,
+            ["mission 0", "mission 1", "mission 3", "mission 4"]
+        );
+        assert_eq!(
+            polled.activities[3].id,
+            Some(batch.results[4].states[1].message.clone())
+        );
+    }
+
+    #[test]
+    fn report_the_presence() {
+        let states = states(vec![
+            Receipt {
+                receiver: "setsuna".to_owned(),
+                queued: Ok("1-0".to_owned()),
+                transient: false,
+                devices: 2,
+            },
+            Receipt {
+                receiver: "lockon".to_owned(),
+                queued: Err("WRONGTYPE".to_owned()),
+                transient: false,
+                devices: 0,
+            },
+        ])
+        .states;
+        assert_eq!(states[0].outcome, activity::Outcome::Queued as i32);
+        assert_eq!(states[0].message, "1-0");
+        assert!(states[0].online);
+        assert_eq!(states[0].devices, 2);
+        assert_eq!(states[1].outcome, activity::Outcome::Failed as i32);
+        assert_eq!(states[1].reason, "WRONGTYPE");
+        assert!(!states[1].online);
+    }
 }
@@ -0,0 +1,274 @@
+use std::{
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
+
+use actix::prelude::*;
+use actix_web::web::Bytes;
+use futures::{
+    channel::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
+    Stream,
+};
+use log::info;
+
+use chrono::Utc;
+
+use crate::{
+    config::CONFIG,
+    entity::{Meister, Remote, SessionId},
+};
+
+use super::{
+    Close, Connect, Disconnect, Heartbeat, IdentitySession, Offline, Online, Redis, Websocket,
+    WsMessage,
+};
+
+/// 通过Server-Sent Events推送消息的session,和websocket session共用注册和推送逻辑
+pub struct SseSession {
+    /// session唯一ID
+    pub id: SessionId,
+    /// 登录的用户
+    pub meister: Meister,
+    /// 客户端信息,连接时登记到Websocket
+    pub remote: Remote,
+    /// 客户端`Last-Event-ID`,之后的消息才会推送
+    pub cursor: Option<String>,
+    pub redis_addr: Addr<Redis>,
+    pub websocket_addr: Addr<Websocket>,
+    /// 写入http响应的body
+    tx: UnboundedSender<Result<Bytes, actix_web::Error>>,
+    /// body被drop(客户端断开)时完成
+    closed: Option<oneshot::Receiver<()>>,
+}
+
+/// sse响应的body,被drop时通知session
+pub struct SseBody {
+    rx: UnboundedReceiver<Result<Bytes, actix_web::Error>>,
+    _closed: oneshot::Sender<()>,
+}
+
+impl Stream for SseBody {
+    type Item = Result<Bytes, actix_web::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.get_mut().rx).poll_next(cx)
+    }
+}
+
+impl SseSession {
+    /// 创建session和写给客户端的body
+    pub fn new(
+        meister: Meister,
+        remote: Remote,
+        cursor: Option<String>,
+        redis_addr: Addr<Redis>,
+        websocket_addr: Addr<Websocket>,
+    ) -> (Self, SseBody) {
+        let (tx, rx) = mpsc::unbounded();
+        let (closed_tx, closed_rx) = oneshot::channel();
+        let session = SseSession {
+            id: SessionId::nil(),
+            meister,
+            remote,
+            cursor,
+            redis_addr,
+            websocket_addr,
+            tx,
+            closed: Some(closed_rx),
+        };
+        (
+            session,
+            SseBody {
+                rx,
+                _closed: closed_tx,
+            },
+        )
+    }
+
+    /// 写一个event,客户端断开时停止session,返回是否写成功
+    fn send(&self, event: String, ctx: &mut Context<Self>) -> Result<(), String> {
+        if self.tx.unbounded_send(Ok(Bytes::from(event))).is_err() {
+            info!("sse client {} disconnected", self.id);
+            ctx.stop();
+            return Err("sse client disconnected".to_owned());
+        }
+        Ok(())
+    }
+}
+
+impl Actor for SseSession {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // 客户端断开后body被drop,立即停止,不等下一次写失败
+        if let Some(closed) = self.closed.take() {
+            closed
+                .into_actor(self)
+                .map(|_, act, ctx| {
+                    info!("sse client {} disconnected", act.id);
+                    ctx.stop();
+                })
+                .spawn(ctx);
+        }
+
+        // 注释行保持代理不断开连接,也用来发现客户端已经断开
+        // sse没有客户端心跳,写成功就当作收到了心跳
+        ctx.run_interval(CONFIG.heartbeat(None).interval, |act, ctx| {
+            let _ = act.send(": ping\n\n".to_owned(), ctx);
+            act.websocket_addr.do_send(Heartbeat {
+                id: act.id,
+                at: Utc::now().timestamp_millis(),
+            });
+        });
+
+        self.websocket_addr
+            .send(Connect {
+                addr: ctx.address().recipient(),
+                close: ctx.address().recipient(),
+                transport: "sse",
+                remote: self.remote.clone(),
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(id) => {
+                        act.id = id;
+                        act.websocket_addr.do_send(IdentitySession {
+                            id,
+                            meister: act.meister.clone(),
+                        });
+                        act.redis_addr.do_send(Online {
+                            id,
+                            meister: act.meister.clone(),
+                            addr: ctx.address().recipient(),
+                            cursor: act.cursor.take(),
+                        });
+                    }
+                    // something is wrong with socket server
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.redis_addr.do_send(Offline { id: self.id });
+        self.websocket_addr.do_send(Disconnect { id: self.id });
+        Running::Stop
+    }
+}
+
+/// 管理员踢下线,客户端收到`kicked`事件
+impl Handler<Close> for SseSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Close, ctx: &mut Self::Context) {
+        let reason = msg.reason.replace('\n', " ");
+        let _ = self.send(format!("event: kicked\ndata: {}\n\n", reason), ctx);
+        ctx.stop();
+    }
+}
+
+/// 每条推送是一个event,`id`为redis stream ID,用于`Last-Event-ID`续传
+impl Handler<WsMessage> for SseSession {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) -> Self::Result {
+        let mut event = String::new();
+        if let Some(id) = msg.last_id {
+            event.push_str(&format!("id: {}\n", id));
+        }
+        for line in msg.msg.lines() {
+            event.push_str(&format!("data: {}\n", line));
+        }
+        event.push('\n');
+        self.send(event, ctx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        addr::{ListSessions, Trial, Webhook},
+        entity::Activity,
+        storage::MemoryStorage,
+    };
+    use futures::StreamExt;
+    use std::{sync::Arc, time::Duration};
+
+    #[actix_rt::test]
+    async fn resume_from_the_last_event() {
+        let webhook = Webhook::new(None).start();
+        let redis_addr =
+            Redis::new(Arc::new(MemoryStorage::default()), webhook.recipient()).start();
+        let mut ids = Vec::new();
+        for activity in ["trans-am", "exia"] {
+            let receipts = redis_addr
+                .send(Trial {
+                    tenant: "celestial".to_owned(),
+                    message: Activity {
+                        activity_type: "mission".to_owned(),
+                        activity: activity.to_owned(),
+                        ..Default::default()
+                    },
+                    receivers: vec!["setsuna".to_owned()],
+                })
+                .await
+                .unwrap()
+                .unwrap();
+            ids.push(receipts[0].queued.clone().unwrap());
+        }
+
+        // the client reconnects with `Last-Event-ID` of the first activity
+        let (session, mut body) = SseSession::new(
+            Meister::new("celestial", "setsuna"),
+            Remote::default(),
+            Some(ids[0].clone()),
+            redis_addr,
+            Websocket::default().start(),
+        );
+        session.start();
+
+        let event = actix_rt::time::timeout(Duration::from_millis(500), body.next())
+            .await
+            .expect("pending activities are pushed at once")
+            .unwrap()
+            .unwrap();
+        let event = std::str::from_utf8(&event).unwrap();
+        assert!(event.starts_with(&format!("id: {}\ndata: ", ids[1])));
+        assert!(event.contains("exia"));
+        assert!(!event.contains("trans-am"));
+    }
+
+    #[actix_rt::test]
+    async fn stop_when_the_client_is_gone() {
+        let webhook = Webhook::new(None).start();
+        let redis_addr =
+            Redis::new(Arc::new(MemoryStorage::default()), webhook.recipient()).start();
+        let websocket_addr = Websocket::default().start();
+        let (session, body) = SseSession::new(
+            Meister::new("celestial", "setsuna"),
+            Remote::default(),
+            None,
+            redis_addr,
+            websocket_addr.clone(),
+        );
+        session.start();
+        let list = || ListSessions {
+            tenant: None,
+            username: None,
+        };
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(websocket_addr.send(list()).await.unwrap().len(), 1);
+
+        // the session leaves before the next heartbeat
+        drop(body);
+        actix_rt::time::sleep(Duration::from_millis(50)).await;
+        assert!(websocket_addr.send(list()).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use actix::prelude::*;
+use awc::Client;
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use log::{info, warn};
+use sha2::Sha256;
+
+use crate::{
+    constants::{WEBHOOK_BACKOFF, WEBHOOK_RETRIES, WEBHOOK_TIMEOUT},
+    entity::DeliveryReceipt,
+};
+
+/// 把消息回执POST到生产者登记的url
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Callback {
+    pub url: String,
+    pub receipt: DeliveryReceipt,
+}
+
+/// 向生产者推送消息回执
+pub struct Webhook {
+    cli: Client,
+    /// 配置后每个请求都带上`X-Veda-Timestamp`和`X-Veda-Signature`
+    secret: Option<String>,
+}
+
+impl Webhook {
+    pub fn new(secret: Option<String>) -> Self {
+        Self {
+            cli: Client::builder().timeout(WEBHOOK_TIMEOUT).finish(),
+            secret,
+        }
+    }
+}
+
+impl Actor for Webhook {
+    type Context = Context<Self>;
+}
+
+/// hex encoded hmac-sha256 of `<timestamp>.<body>`,
+/// 接收方拒绝时间太久的请求就能防止重放
+fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac takes key of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+impl Handler<Callback> for Webhook {
+    type Result = ();
+
+    fn handle(&mut self, msg: Callback, _: &mut Self::Context) -> Self::Result {
+        let body = match serde_json::to_vec(&msg.receipt) {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let (secret, cli) = (self.secret.clone(), self.cli.clone());
+
+        actix::spawn(async move {
+            let mut backoff = WEBHOOK_BACKOFF;
+            for attempt in 1..=WEBHOOK_RETRIES {
+                let mut req = cli
+                    .post(&msg.url)
+                    .insert_header(("Content-Type", "application/json"));
+                // 每次重试重新签名,时间戳是发送的时间
+                if let Some(secret) = &secret {
+                    let timestamp = Utc::now().timestamp();
+                    req = req
+                        .insert_header(("X-Veda-Timestamp", timestamp.to_string()))
+                        .insert_header((
+                            "X-Veda-Signature",
+                            format!("sha256={}", sign(secret, timestamp, &body)),
+                        ));
+                }
+
+                match req.send_body(body.clone()).await {
+                    Ok(res) if res.status().is_success() => return,
+                    Ok(res) => warn!(
+                        "webhook {} answered {} (attempt {})",
+                        &msg.url,
+                        res.status(),
+                        attempt
+                    ),
+                    Err(e) => warn!("webhook {} failed: {} (attempt {})", &msg.url, e, attempt),
+                }
+
+                actix_web::rt::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            info!(
+                "give up posting {:?} of `{}` to {}",
+                msg.receipt.event, &msg.receipt.message_id, &msg.url
+            );
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_a_body() {
+        // the key of RFC 4231 test case 2, signing `<timestamp>.<body>`
+        assert_eq!(
+            sign("Jefe", 1600000000, b"what do ya want for nothing?"),
+            "b85f25f16e04566c45b56e251f157f42571b374814eef6634ae7dfee9ea725e7"
+        );
+    }
+}
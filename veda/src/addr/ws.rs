@@ -1,81 +1,189 @@
 use actix::prelude::*;
 use actix_web_actors::ws;
+use chrono::Utc;
 use log::{debug, info};
-use rand::{prelude::ThreadRng, Rng};
 
 use std::{collections::HashMap, time::Instant};
 
 use crate::{
     addr::PlatformOnline,
-    constants::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL},
+    config::{HeartbeatPolicy, CONFIG},
+    eio::{self, Packet, Protocol, SocketIo},
+    entity::{
+        validate_stream_id, DeliveryEvent, Meister, Parameter, Platform, Remote, SessionId,
+        SessionInfo,
+    },
 };
+use serde_json::{json, Value};
+use validator::Validate;
 
-use super::{Offline, Online, Redis, Seravee};
+use super::{Acknowledge, Offline, Online, Redis};
+/// 推送给客户端的文本,失败时返回原因,消息不会被确认
+#[derive(Message)]
+#[rtype(result = "Result<(), String>")]
+pub struct WsMessage {
+    pub msg: String,
+    /// 来自redis stream时,最后一条消息的ID
+    pub last_id: Option<String>,
+}
+
+/// 管理员关闭session,`reason`发给客户端
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct WsMessage(pub String);
+pub struct Close {
+    pub reason: String,
+}
 
-/// 接入websocket服务
+/// 接入websocket服务,返回分配的session ID
 #[derive(Message, Debug)]
-#[rtype(usize)]
+#[rtype(result = "SessionId")]
 pub struct Connect {
     pub addr: Recipient<WsMessage>,
+    pub close: Recipient<Close>,
+    /// `websocket`,`socket.io`或`sse`
+    pub transport: &'static str,
+    pub remote: Remote,
 }
 
 /// 断开websocket服务
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct Disconnect {
-    pub id: usize,
+    pub id: SessionId,
 }
-/// 告诉Studio当前session的name
+/// 告诉Websocket当前session登录的用户
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct IdentitySession {
-    pub id: usize,
-    pub name: String,
+    pub id: SessionId,
+    pub meister: Meister,
+}
+
+/// 告诉Websocket当前session的设备平台
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct SessionPlatform {
+    pub id: SessionId,
+    pub platform: String,
+}
+
+/// 客户端心跳,`at`为毫秒时间
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Heartbeat {
+    pub id: SessionId,
+    pub at: i64,
+}
+
+/// 关闭租户内指定的session,或者符合`username`和`platform`的所有session,
+/// 返回关闭的session数量
+#[derive(Message, Debug)]
+#[rtype(usize)]
+pub struct Kick {
+    pub session: Option<SessionId>,
+    pub tenant: String,
+    pub username: Option<String>,
+    pub platform: Option<String>,
+    pub reason: String,
+}
+
+impl Kick {
+    /// 空字符串表示不指定,不指定session时用户名和平台至少要有一个
+    pub fn new(
+        session: &str,
+        tenant: &str,
+        username: &str,
+        platform: &str,
+        reason: &str,
+    ) -> Result<Self, String> {
+        let given = |value: &str| Some(value.to_owned()).filter(|value| !value.is_empty());
+        let session = match session {
+            "" => None,
+            session => Some(
+                session
+                    .parse()
+                    .map_err(|_| format!("invalid session: {}", session))?,
+            ),
+        };
+        let (username, platform) = (given(username), given(platform));
+        if session.is_none() && username.is_none() && platform.is_none() {
+            return Err("session, username or platform is required".to_owned());
+        }
+        Ok(Self {
+            session,
+            tenant: tenant.to_owned(),
+            username,
+            platform,
+            reason: given(reason).unwrap_or_else(|| "kicked by admin".to_owned()),
+        })
+    }
 }
 
 /// 告诉Studio当前session的name
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct RedisMessage {
-    pub id: usize,
+    pub id: SessionId,
     pub msg: String,
 }
 
-/// 显示在线的names
-pub struct ListNames;
+/// 显示租户内在线的names
+pub struct ListNames {
+    pub tenant: String,
+}
 
 impl actix::Message for ListNames {
     type Result = Vec<String>;
 }
 
+/// 向租户内所有已登录的session发送消息,返回session数量
+#[derive(Message, Debug)]
+#[rtype(usize)]
+pub struct Broadcast {
+    pub tenant: String,
+    pub msg: String,
+}
+
+/// 列出在线的session,`tenant`和`username`为空时不过滤
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<SessionInfo>")]
+pub struct ListSessions {
+    pub tenant: Option<String>,
+    pub username: Option<String>,
+}
+
+/// 一个在线的session
+struct Peer {
+    /// 推送消息的地址
+    addr: Recipient<WsMessage>,
+    /// 关闭session的地址
+    close: Recipient<Close>,
+    info: SessionInfo,
+}
+
+#[derive(Default)]
 pub struct Websocket {
-    //链接信息
-    // soc_sessions.key: websocket session的id
-    // soc_sessions.value: websocket 接受参数地址
-    sessions: HashMap<usize, Recipient<WsMessage>>,
-    // red_sessions.key: redis steam session的id
-    rng: ThreadRng,
-}
-
-impl Default for Websocket {
-    fn default() -> Self {
-        Self {
-            sessions: HashMap::with_capacity(1),
-            rng: rand::thread_rng(),
-        }
-    }
+    // key: session ID
+    sessions: HashMap<SessionId, Peer>,
 }
 
 impl Websocket {
-    /// 发送消息到指定name的所有客户端
-    fn send_message(&self, id: usize, message: &str) {
-        if let Some(addr) = self.sessions.get(&id) {
-            let _ = addr.do_send(WsMessage(message.to_owned()));
+    /// 发送消息到指定的session
+    fn send_message(&self, id: SessionId, message: &str) {
+        if let Some(peer) = self.sessions.get(&id) {
+            let _ = peer.addr.do_send(WsMessage {
+                msg: message.to_owned(),
+                last_id: None,
+            });
         }
     }
+
+    /// 已登录的session和用户
+    fn meisters(&self) -> impl Iterator<Item = (&SessionId, &Meister)> {
+        self.sessions
+            .iter()
+            .filter_map(|(id, peer)| peer.info.meister.as_ref().map(|meister| (id, meister)))
+    }
 }
 
 impl Actor for Websocket {
@@ -83,14 +191,37 @@ impl Actor for Websocket {
 }
 
 impl Handler<Connect> for Websocket {
-    type Result = usize;
+    type Result = MessageResult<Connect>;
 
     fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
-        let id = self.rng.gen::<usize>();
-        info!("websocket connection {} connected", id);
-        self.sessions.insert(id, msg.addr);
+        let mut id = SessionId::new_v4();
+        while self.sessions.contains_key(&id) {
+            id = SessionId::new_v4();
+        }
+        info!(
+            "{} connection {} connected from {:?}",
+            msg.transport, id, &msg.remote.addr
+        );
+        let now = Utc::now().timestamp_millis();
+        self.sessions.insert(
+            id,
+            Peer {
+                addr: msg.addr,
+                close: msg.close,
+                info: SessionInfo {
+                    id,
+                    transport: msg.transport,
+                    connected_at: now,
+                    remote_addr: msg.remote.addr,
+                    user_agent: msg.remote.user_agent,
+                    meister: None,
+                    platform: None,
+                    last_heartbeat: now,
+                },
+            },
+        );
         // 新的连接会增加连接数量,不一定会引起用户数量增加
-        id
+        MessageResult(id)
     }
 }
 
@@ -99,7 +230,135 @@ impl Handler<Disconnect> for Websocket {
 
     fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) -> Self::Result {
         self.sessions.remove(&msg.id);
-        info!("name:{:?} disconnected", &msg.id);
+        info!("session {} disconnected", &msg.id);
+    }
+}
+
+impl Handler<IdentitySession> for Websocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: IdentitySession, _: &mut Self::Context) -> Self::Result {
+        if let Some(peer) = self.sessions.get_mut(&msg.id) {
+            peer.info.meister = Some(msg.meister);
+        }
+    }
+}
+
+impl Handler<SessionPlatform> for Websocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: SessionPlatform, _: &mut Self::Context) -> Self::Result {
+        if let Some(peer) = self.sessions.get_mut(&msg.id) {
+            peer.info.platform = Some(msg.platform);
+        }
+    }
+}
+
+impl Handler<Heartbeat> for Websocket {
+    type Result = ();
+
+    fn handle(&mut self, msg: Heartbeat, _: &mut Self::Context) -> Self::Result {
+        if let Some(peer) = self.sessions.get_mut(&msg.id) {
+            peer.info.last_heartbeat = msg.at;
+        }
+    }
+}
+
+impl Handler<ListSessions> for Websocket {
+    type Result = MessageResult<ListSessions>;
+
+    fn handle(&mut self, msg: ListSessions, _: &mut Self::Context) -> Self::Result {
+        let matches = |value: &str, filter: &Option<String>| {
+            filter.as_ref().map_or(true, |filter| value == filter)
+        };
+        let mut sessions: Vec<SessionInfo> = self
+            .sessions
+            .values()
+            .map(|peer| &peer.info)
+            .filter(|info| match &info.meister {
+                Some(meister) => {
+                    matches(&meister.tenant, &msg.tenant)
+                        && matches(&meister.username, &msg.username)
+                }
+                None => msg.tenant.is_none() && msg.username.is_none(),
+            })
+            .cloned()
+            .collect();
+        sessions.sort_by_key(|info| info.connected_at);
+        MessageResult(sessions)
+    }
+}
+
+impl Handler<Kick> for Websocket {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Kick, _: &mut Self::Context) -> Self::Result {
+        // 指定session时也要在租户内,管理员只能关闭有权限的租户的session,
+        // 还没有登录的session不属于任何租户
+        let ids: Vec<SessionId> = self
+            .meisters()
+            .filter(|(id, _)| msg.session.map_or(true, |session| **id == session))
+            .filter(|(_, meister)| meister.tenant == msg.tenant)
+            .filter(|(_, meister)| {
+                msg.username
+                    .as_ref()
+                    .map_or(true, |username| &meister.username == username)
+            })
+            .filter(|(id, _)| {
+                msg.platform.as_ref().map_or(true, |platform| {
+                    self.sessions[id]
+                        .info
+                        .platform
+                        .as_ref()
+                        .map_or(false, |p| p.eq_ignore_ascii_case(platform))
+                })
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut kicked = 0;
+        for id in ids {
+            if let Some(peer) = self.sessions.get(&id) {
+                info!("kick session {}: {}", id, &msg.reason);
+                let _ = peer.close.do_send(Close {
+                    reason: msg.reason.clone(),
+                });
+                kicked += 1;
+            }
+        }
+        kicked
+    }
+}
+
+impl Handler<ListNames> for Websocket {
+    type Result = Vec<String>;
+
+    fn handle(&mut self, msg: ListNames, _: &mut Self::Context) -> Self::Result {
+        let mut names: Vec<String> = self
+            .meisters()
+            .map(|(_, meister)| meister)
+            .filter(|meister| meister.tenant == msg.tenant)
+            .map(|meister| meister.username.clone())
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+impl Handler<Broadcast> for Websocket {
+    type Result = usize;
+
+    fn handle(&mut self, msg: Broadcast, _: &mut Self::Context) -> Self::Result {
+        let ids: Vec<SessionId> = self
+            .meisters()
+            .filter(|(_, meister)| meister.tenant == msg.tenant)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &ids {
+            self.send_message(*id, &msg.msg);
+        }
+        ids.len()
     }
 }
 
@@ -111,16 +370,66 @@ impl Handler<RedisMessage> for Websocket {
     }
 }
 
+/// websocket session的状态
+#[derive(Debug, PartialEq)]
+pub enum SessionState {
+    /// 等待Websocket分配session ID,收到的文本帧先排队
+    Connecting(Vec<String>),
+    /// 已分配session ID,还没有登录
+    Anonymous,
+    /// 已登录,同一个连接不能再登录
+    Authenticated(Meister),
+    /// 正在关闭,不再处理客户端消息
+    Closing,
+}
+
+impl SessionState {
+    /// 登录的用户
+    pub fn meister(&self) -> Option<&Meister> {
+        match self {
+            SessionState::Authenticated(meister) => Some(meister),
+            _ => None,
+        }
+    }
+
+    /// 只有匿名的session可以登录
+    fn login(&mut self, meister: Meister) -> Result<(), String> {
+        match self {
+            SessionState::Anonymous => {
+                *self = SessionState::Authenticated(meister);
+                Ok(())
+            }
+            SessionState::Authenticated(current) if *current == meister => {
+                Err(format!("already logged in as `{}`", current))
+            }
+            SessionState::Authenticated(current) => Err(format!(
+                "logged in as `{}`, reconnect to login as `{}`",
+                current, meister
+            )),
+            SessionState::Connecting(_) => Err("session is connecting".to_owned()),
+            SessionState::Closing => Err("session is closing".to_owned()),
+        }
+    }
+}
+
 pub struct WebsocketSession {
-    /// session唯一ID
-    pub id: usize,
-    pub name: Option<String>,
+    /// session唯一ID,连接到Websocket之后分配
+    pub id: SessionId,
+    /// 连接和登录状态
+    pub state: SessionState,
+    /// 客户端信息,连接时登记到Websocket
+    pub remote: Remote,
     /// session内部计时器,用于定时向客户端ping
     pub hb: Instant,
+    /// 心跳策略,登记设备平台后可能改变
+    pub heartbeat: HeartbeatPolicy,
+    /// 心跳定时器,心跳策略改变时重新启动
+    pub hb_handle: Option<SpawnHandle>,
+    /// 客户端使用的协议
+    pub protocol: Protocol,
     /// websocket addr
     pub redis_addr: Addr<Redis>,
     pub websocket_addr: Addr<Websocket>,
-    pub grpc_addr: Addr<Seravee>,
 }
 
 impl Actor for WebsocketSession {
@@ -140,14 +449,42 @@ impl Actor for WebsocketSession {
         let addr = ctx.address();
         self.websocket_addr
             .send(Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                close: addr.recipient(),
+                transport: match self.protocol {
+                    Protocol::Veda => "websocket",
+                    Protocol::EngineIo { .. } => "socket.io",
+                },
+                remote: self.remote.clone(),
             })
             .into_actor(self)
             .then(|res, act, ctx| {
                 match res {
                     Ok(res) => act.id = res,
                     // something is wrong with socket server
-                    _ => ctx.stop(),
+                    _ => {
+                        act.state = SessionState::Closing;
+                        ctx.stop();
+                        return fut::ready(());
+                    }
+                }
+                // engine.io v3客户端不会主动连接默认namespace
+                if let Protocol::EngineIo { version } = act.protocol {
+                    ctx.text(eio::open(&act.id.to_string(), &act.heartbeat));
+                    if version < 4 {
+                        ctx.text(eio::connect(version, &act.id.to_string()));
+                    }
+                }
+                // 分配ID之前收到的消息按顺序处理
+                let queued = match std::mem::replace(&mut act.state, SessionState::Anonymous) {
+                    SessionState::Connecting(queued) => queued,
+                    state => {
+                        act.state = state;
+                        Vec::new()
+                    }
+                };
+                for text in queued {
+                    act.text(&text, ctx);
                 }
                 fut::ready(())
             })
@@ -155,6 +492,7 @@ impl Actor for WebsocketSession {
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.state = SessionState::Closing;
         // notify redis server
         &self.redis_addr.do_send(Offline { id: self.id });
         // notify socket server
@@ -165,10 +503,32 @@ impl Actor for WebsocketSession {
 
 /// Handle messages from socket server, we simply send it to peer server
 impl Handler<WsMessage> for WebsocketSession {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) -> Self::Result {
+        match self.protocol {
+            Protocol::Veda => ctx.text(msg.msg),
+            Protocol::EngineIo { .. } => ctx.text(eio::event("message", &msg.msg)),
+        }
+        Ok(())
+    }
+}
+
+/// 管理员踢下线,engine.io客户端先收到socket.io的disconnect
+impl Handler<Close> for WebsocketSession {
     type Result = ();
 
-    fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+    fn handle(&mut self, msg: Close, ctx: &mut Self::Context) {
+        if let Protocol::EngineIo { .. } = self.protocol {
+            ctx.text(eio::event("kicked", &json!(&msg.reason).to_string()));
+            ctx.text("41");
+        }
+        self.state = SessionState::Closing;
+        ctx.close(Some(ws::CloseReason {
+            code: ws::CloseCode::Policy,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
     }
 }
 
@@ -192,45 +552,10 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketSession
             ws::Message::Pong(_) => {
                 self.hb = Instant::now();
             }
-            ws::Message::Text(text) => {
-                let m = text.trim();
-                // we check for /sss type of messages
-                if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    if let "/login" = v[0] {
-                        if v.len() == 2 {
-                            let name = v[1].to_owned();
-                            self.name = Some(name.clone());
-                            self.redis_addr.do_send(Online {
-                                id: self.id,
-                                name,
-                                addr: ctx.address().recipient(),
-                            });
-                        } else {
-                            ctx.text("!!! name is required");
-                        }
-                    } else if let "/platform" = v[0] {
-                        if v.len() == 2 {
-                            let device = serde_json::from_str(v[1]).expect("error device info");
-                            if let Some(username) = &self.name {
-                                self.redis_addr.do_send(PlatformOnline {
-                                    id: self.id,
-                                    name: username.to_string(),
-                                    platform: device,
-                                });
-                            }
-                        } else {
-                            ctx.text("!!! platform is required");
-                        }
-                    } else {
-                        ctx.text(format!("!!! unknown command: {:?}", m))
-                    }
-                } else {
-                    ctx.text(format!("!!! unknown command: {:?}", m));
-                }
-            }
+            ws::Message::Text(text) => self.text(text.trim(), ctx),
             ws::Message::Binary(_) => info!("Unexpected binary"),
             ws::Message::Close(reason) => {
+                self.state = SessionState::Closing;
                 ctx.close(reason);
                 ctx.stop();
             }
@@ -242,13 +567,208 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebsocketSession
     }
 }
 
+/// `/login <name> [tenant=<tenant>] [last=<stream id>]`,
+/// `last`为重连前收到的最后一条消息的ID
+fn parse_login(args: &str) -> Result<(Meister, Option<String>), String> {
+    let mut args = args.split_whitespace();
+    let mut meister = Meister::new("", args.next().unwrap_or_default());
+    let mut cursor = None;
+    for option in args {
+        match option.split_once('=') {
+            Some(("tenant", tenant)) => meister.tenant = tenant.to_owned(),
+            Some(("last", last)) => {
+                validate_stream_id(last).map_err(|_| format!("invalid stream id: {}", last))?;
+                cursor = Some(last.to_owned());
+            }
+            _ => return Err(format!("unknown login option: {}", option)),
+        }
+    }
+    meister.validate().map_err(|e| e.to_string())?;
+    Ok((meister, cursor))
+}
+
+/// 连接中最多排队的文本帧,超过后拒绝
+const MAX_QUEUED: usize = 16;
+
 impl WebsocketSession {
-    /// helper method that sends ping to client every second.
+    /// 按状态处理客户端的文本帧
+    fn text(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        match &mut self.state {
+            SessionState::Connecting(queued) if queued.len() < MAX_QUEUED => {
+                queued.push(text.to_owned());
+                return;
+            }
+            SessionState::Connecting(_) => {
+                return self.error("session is connecting, try again later", ctx)
+            }
+            SessionState::Closing => return,
+            _ => (),
+        }
+        match self.protocol {
+            Protocol::Veda => {
+                if let Err(e) = self.command(text, ctx) {
+                    self.error(&e, ctx);
+                }
+            }
+            Protocol::EngineIo { version } => self.engine_io(version, text, ctx),
+        }
+    }
+
+    /// 把错误发给客户端
+    fn error(&self, e: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        match self.protocol {
+            Protocol::Veda => ctx.text(format!("!!! {}", e)),
+            Protocol::EngineIo { .. } => ctx.text(eio::event("error", &json!(e).to_string())),
+        }
+    }
+
+    /// 处理`/sss`格式的命令
+    fn command(&mut self, m: &str, ctx: &mut ws::WebsocketContext<Self>) -> Result<(), String> {
+        // we check for /sss type of messages
+        if !m.starts_with('/') {
+            return Err(format!("unknown command: {:?}", m));
+        }
+        let v: Vec<&str> = m.splitn(2, ' ').collect();
+        match v[0] {
+            "/login" => {
+                let (meister, cursor) = parse_login(v.get(1).ok_or("name is required")?)?;
+                self.login(meister, cursor, ctx)?;
+            }
+            // 不能发送websocket ping的客户端使用
+            "/ping" => {
+                self.hb = Instant::now();
+                ctx.text("/pong");
+            }
+            "/platform" => {
+                let device: Platform =
+                    serde_json::from_str(v.get(1).ok_or("platform is required")?)
+                        .map_err(|e| format!("invalid platform: {}", e))?;
+                let meister = self.state.meister().ok_or("login is required")?.clone();
+                let heartbeat = CONFIG.heartbeat(Some(device.name()));
+                if heartbeat != self.heartbeat {
+                    self.heartbeat = heartbeat;
+                    self.hb(ctx);
+                }
+                self.websocket_addr.do_send(SessionPlatform {
+                    id: self.id,
+                    platform: device.name().to_owned(),
+                });
+                self.redis_addr.do_send(PlatformOnline {
+                    id: self.id,
+                    meister,
+                    platform: device,
+                });
+            }
+            "/read" | "/ack" => {
+                let meister = self.state.meister().ok_or("login is required")?;
+                let id = v.get(1).ok_or("message id is required")?;
+                self.redis_addr.do_send(Acknowledge {
+                    meister: meister.clone(),
+                    id: id.trim().to_owned(),
+                    event: if v[0] == "/read" {
+                        DeliveryEvent::Read
+                    } else {
+                        DeliveryEvent::Acked
+                    },
+                });
+            }
+            _ => return Err(format!("unknown command: {:?}", m)),
+        }
+        Ok(())
+    }
+
+    fn login(
+        &mut self,
+        meister: Meister,
+        cursor: Option<String>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> Result<(), String> {
+        self.state.login(meister.clone())?;
+        self.websocket_addr.do_send(IdentitySession {
+            id: self.id,
+            meister: meister.clone(),
+        });
+        self.redis_addr.do_send(Online {
+            id: self.id,
+            meister,
+            addr: ctx.address().recipient(),
+            cursor,
+        });
+        Ok(())
+    }
+
+    /// 处理engine.io报文
+    fn engine_io(&mut self, version: u8, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let packet = match eio::decode(text) {
+            Ok(packet) => packet,
+            Err(e) => return self.error(&e, ctx),
+        };
+        match packet {
+            Packet::Ping(data) => {
+                self.hb = Instant::now();
+                ctx.text(format!("3{}", data));
+            }
+            Packet::Pong(_) => self.hb = Instant::now(),
+            Packet::Close | Packet::Message(SocketIo::Disconnect) => {
+                self.state = SessionState::Closing;
+                ctx.close(None);
+                ctx.stop();
+            }
+            Packet::Message(SocketIo::Connect) if version >= 4 => {
+                ctx.text(eio::connect(version, &self.id.to_string()))
+            }
+            Packet::Message(SocketIo::Event { ack, name, args }) => {
+                match self.event(&name, &args, ctx) {
+                    Ok(()) => {
+                        if let Some(id) = ack {
+                            ctx.text(eio::ack(id));
+                        }
+                    }
+                    Err(e) => self.error(&e, ctx),
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// `emit`是老前端的base64参数,其他event名对应veda的命令
+    fn event(
+        &mut self,
+        name: &str,
+        args: &[Value],
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> Result<(), String> {
+        let arg = match args.first() {
+            Some(Value::String(arg)) => arg.to_owned(),
+            Some(arg) => arg.to_string(),
+            None => String::new(),
+        };
+        if name != "emit" {
+            return self.command(format!("/{} {}", name, arg).trim(), ctx);
+        }
+
+        let parameter = Parameter::decode(&arg)?;
+        match parameter.method.as_str() {
+            "login" => {
+                let meister = parameter.meister();
+                meister.validate().map_err(|e| e.to_string())?;
+                self.login(meister, None, ctx)
+            }
+            // 离线消息登录后会自动推送
+            "offilneMsg" => Ok(()),
+            method => Err(format!("unknown method: {:?}", method)),
+        }
+    }
+
+    /// helper method that sends ping to client every `heartbeat.interval`.
     /// also this method checks pongs from client
-    fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+    fn hb(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if let Some(handle) = self.hb_handle.take() {
+            ctx.cancel_future(handle);
+        }
+        let handle = ctx.run_interval(self.heartbeat.interval, |act, ctx| {
             // check client heartbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+            if Instant::now().duration_since(act.hb) > act.heartbeat.timeout {
                 // heartbeat timed out
                 info!("websocket client heartbeat failed, disconnecting!");
 
@@ -260,7 +780,214 @@ impl WebsocketSession {
                 return;
             }
 
+            act.websocket_addr.do_send(Heartbeat {
+                id: act.id,
+                at: Utc::now().timestamp_millis() - act.hb.elapsed().as_millis() as i64,
+            });
             ctx.ping(b"");
+            // engine.io v4由服务端发起ping
+            if matches!(act.protocol, Protocol::EngineIo { version } if version >= 4) {
+                ctx.text("2");
+            }
         });
+        self.hb_handle = Some(handle);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+        StreamExt,
+    };
+
+    /// 把推送和关闭转发到channel
+    struct Client(UnboundedSender<String>);
+
+    impl Actor for Client {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WsMessage> for Client {
+        type Result = Result<(), String>;
+
+        fn handle(&mut self, msg: WsMessage, _: &mut Self::Context) -> Self::Result {
+            let _ = self.0.unbounded_send(msg.msg);
+            Ok(())
+        }
+    }
+
+    impl Handler<Close> for Client {
+        type Result = ();
+
+        fn handle(&mut self, msg: Close, _: &mut Self::Context) {
+            let _ = self.0.unbounded_send(format!("close: {}", msg.reason));
+        }
+    }
+
+    /// 连接并登录一个session
+    async fn login(
+        srv: &Addr<Websocket>,
+        meister: Meister,
+        remote: Remote,
+    ) -> (SessionId, UnboundedReceiver<String>) {
+        let (tx, rx) = unbounded();
+        let client = Client(tx).start();
+        let id = srv
+            .send(Connect {
+                addr: client.clone().recipient(),
+                close: client.recipient(),
+                transport: "websocket",
+                remote,
+            })
+            .await
+            .unwrap();
+        srv.send(IdentitySession { id, meister }).await.unwrap();
+        (id, rx)
+    }
+
+    #[test]
+    fn parse_a_login() {
+        assert_eq!(
+            parse_login("gandum"),
+            Ok((Meister::new("", "gandum"), None))
+        );
+        assert_eq!(
+            parse_login("gandum tenant=celestial last=1526919030474-55"),
+            Ok((
+                Meister::new("celestial", "gandum"),
+                Some("1526919030474-55".to_owned())
+            ))
+        );
+        assert!(parse_login("gandum last=0").is_err());
+        assert!(parse_login("gandum team=celestial").is_err());
+    }
+
+    #[test]
+    fn login_only_once() {
+        let gandum = Meister::new("celestial", "gandum");
+        let mut state = SessionState::Connecting(Vec::new());
+        assert!(state.login(gandum.clone()).is_err());
+
+        state = SessionState::Anonymous;
+        assert!(state.login(gandum.clone()).is_ok());
+        assert_eq!(state.meister(), Some(&gandum));
+        assert!(state.login(gandum.clone()).is_err());
+        assert!(state.login(Meister::new("celestial", "exia")).is_err());
+        assert_eq!(state.meister(), Some(&gandum));
+    }
+
+    #[test]
+    fn build_a_kick() {
+        let kick = Kick::new("", "celestial", "gandum", "", "").unwrap();
+        assert_eq!(kick.session, None);
+        assert_eq!(kick.username.as_deref(), Some("gandum"));
+        assert_eq!(kick.reason, "kicked by admin");
+
+        let session = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+        assert_eq!(
+            Kick::new(session, "", "", "", "").unwrap().session,
+            session.parse().ok()
+        );
+        assert!(Kick::new("42", "", "", "", "").is_err());
+        assert!(Kick::new("", "celestial", "", "", "").is_err());
+        assert!(Kick::new("gandum", "", "", "", "").is_err());
+    }
+
+    #[actix_rt::test]
+    async fn isolate_the_tenants() {
+        let srv = Websocket::default().start();
+        let (celestial, mut setsuna) = login(
+            &srv,
+            Meister::new("celestial", "setsuna"),
+            Remote::default(),
+        )
+        .await;
+        let (_, mut namesake) = login(
+            &srv,
+            Meister::new("ptolemaios", "setsuna"),
+            Remote::default(),
+        )
+        .await;
+
+        let names = |tenant: &str| {
+            srv.send(ListNames {
+                tenant: tenant.to_owned(),
+            })
+        };
+        assert_eq!(names("celestial").await.unwrap(), ["setsuna"]);
+        assert!(names("").await.unwrap().is_empty());
+
+        let sessions = srv
+            .send(Broadcast {
+                tenant: "ptolemaios".to_owned(),
+                msg: "trans-am".to_owned(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(sessions, 1);
+        assert_eq!(namesake.next().await.as_deref(), Some("trans-am"));
+
+        // a session of another tenant can't be kicked by its id
+        let kick = Kick::new(&celestial.to_string(), "ptolemaios", "", "", "").unwrap();
+        assert_eq!(srv.send(kick).await.unwrap(), 0);
+        let kick = Kick::new(&celestial.to_string(), "celestial", "", "", "").unwrap();
+        assert_eq!(srv.send(kick).await.unwrap(), 1);
+        assert_eq!(
+            setsuna.next().await.as_deref(),
+            Some("close: kicked by admin")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn record_the_session_metadata() {
+        let srv = Websocket::default().start();
+        let (id, _rx) = login(
+            &srv,
+            Meister::new("celestial", "setsuna"),
+            Remote {
+                addr: Some("10.0.0.7".to_owned()),
+                user_agent: Some("exia/1.0".to_owned()),
+            },
+        )
+        .await;
+        let (other, _other_rx) =
+            login(&srv, Meister::new("celestial", "lockon"), Remote::default()).await;
+        assert_ne!(id, other);
+        srv.send(SessionPlatform {
+            id,
+            platform: "Android".to_owned(),
+        })
+        .await
+        .unwrap();
+        srv.send(Heartbeat { id, at: 42 }).await.unwrap();
+
+        let sessions = srv
+            .send(ListSessions {
+                tenant: Some("celestial".to_owned()),
+                username: Some("setsuna".to_owned()),
+            })
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        let session = &sessions[0];
+        assert_eq!(session.id, id);
+        assert_eq!(session.transport, "websocket");
+        assert_eq!(session.remote_addr.as_deref(), Some("10.0.0.7"));
+        assert_eq!(session.user_agent.as_deref(), Some("exia/1.0"));
+        assert_eq!(session.platform.as_deref(), Some("Android"));
+        assert_eq!(session.last_heartbeat, 42);
+
+        srv.send(Disconnect { id }).await.unwrap();
+        let sessions = srv
+            .send(ListSessions {
+                tenant: None,
+                username: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].id, other);
     }
 }
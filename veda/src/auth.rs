@@ -0,0 +1,207 @@
+//! grpc和http生产者的认证和授权,生产者登记在json文件里
+use log::info;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tonic::{service::Interceptor, Request, Status};
+
+use std::sync::Arc;
+
+use crate::addr::Trial;
+
+/// hex encoded sha256
+fn sha256(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// 登记的生产者
+#[derive(Clone, Debug, Deserialize)]
+pub struct Producer {
+    /// 生产者身份,用于限流和消息的`producer`
+    pub name: String,
+    /// api key的sha256,客户端在`x-veda-api-key`里发送api key
+    #[serde(default)]
+    pub api_key_sha256: Option<String>,
+    /// mTLS客户端证书(DER)的sha256
+    #[serde(default)]
+    pub cert_sha256: Option<String>,
+    /// 可以发送的消息类型,为空时不限制
+    #[serde(default)]
+    pub activity_types: Option<Vec<String>>,
+    /// 可以发送到的租户,为空时不限制
+    #[serde(default)]
+    pub tenants: Option<Vec<String>>,
+    /// 可以使用管理接口,如踢用户下线
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl Producer {
+    pub fn allows_activity_type(&self, activity_type: &str) -> bool {
+        self.activity_types
+            .as_ref()
+            .map_or(true, |types| types.iter().any(|t| t == activity_type))
+    }
+
+    pub fn allows_tenant(&self, tenant: &str) -> bool {
+        self.tenants
+            .as_ref()
+            .map_or(true, |tenants| tenants.iter().any(|t| t == tenant))
+    }
+
+    /// 检查生产者能不能发送这条消息
+    pub fn authorize(&self, trial: &Trial) -> Result<(), String> {
+        self.permit(&trial.message.activity_type, &trial.tenant)
+    }
+
+    /// 检查生产者能不能向租户发送这种消息
+    pub fn permit(&self, activity_type: &str, tenant: &str) -> Result<(), String> {
+        if !self.allows_activity_type(activity_type) {
+            return Err(format!(
+                "producer `{}` can't send `{}`",
+                &self.name, activity_type
+            ));
+        }
+        if !self.allows_tenant(tenant) {
+            return Err(format!(
+                "producer `{}` can't send to tenant `{}`",
+                &self.name, tenant
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// 所有登记的生产者
+#[derive(Debug, Default)]
+pub struct Producers {
+    producers: Vec<Producer>,
+}
+
+impl Producers {
+    /// 从json文件加载,内容为`Producer`数组
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let producers: Vec<Producer> =
+            serde_json::from_str(&file).map_err(|e| format!("{}: {}", path, e))?;
+        if let Some(producer) = producers
+            .iter()
+            .find(|p| p.api_key_sha256.is_none() && p.cert_sha256.is_none())
+        {
+            return Err(format!(
+                "{}: producer `{}` has neither api_key_sha256 nor cert_sha256",
+                path, &producer.name
+            ));
+        }
+        info!("loaded {} producers from {}", producers.len(), path);
+        Ok(Self { producers })
+    }
+
+    /// 按api key或者客户端证书找到生产者
+    pub fn authenticate(&self, api_key: Option<&str>, cert: Option<&[u8]>) -> Option<&Producer> {
+        let api_key = api_key.map(|key| sha256(key.as_bytes()));
+        let cert = cert.map(sha256);
+        self.producers.iter().find(|producer| {
+            let matches =
+                |expected: &Option<String>, actual: &Option<String>| match (expected, actual) {
+                    (Some(expected), Some(actual)) => expected.eq_ignore_ascii_case(actual),
+                    _ => false,
+                };
+            matches(&producer.api_key_sha256, &api_key) || matches(&producer.cert_sha256, &cert)
+        })
+    }
+}
+
+/// grpc拦截器,认证通过后把`Producer`放到request的extensions里.
+/// 没有登记生产者时不认证
+#[derive(Clone)]
+pub struct Authenticator {
+    pub producers: Option<Arc<Producers>>,
+}
+
+impl Authenticator {
+    /// 没有登记生产者时为`None`,登记了生产者时必须认证通过
+    pub fn authenticate(
+        &self,
+        api_key: Option<&str>,
+        cert: Option<&[u8]>,
+    ) -> Result<Option<Producer>, String> {
+        match &self.producers {
+            Some(producers) => producers
+                .authenticate(api_key, cert)
+                .cloned()
+                .map(Some)
+                .ok_or_else(|| "unknown producer".to_owned()),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Interceptor for Authenticator {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        let api_key = request
+            .metadata()
+            .get("x-veda-api-key")
+            .and_then(|key| key.to_str().ok());
+        let certs = request.peer_certs();
+        let cert = certs
+            .as_ref()
+            .and_then(|certs| certs.first())
+            .map(|cert| cert.as_ref());
+        if let Some(producer) = self
+            .authenticate(api_key, cert)
+            .map_err(Status::unauthenticated)?
+        {
+            request.extensions_mut().insert(producer);
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::Activity;
+
+    #[test]
+    fn authenticate_a_producer() {
+        let producers = Producers {
+            producers: vec![Producer {
+                name: "his".to_owned(),
+                api_key_sha256: Some(sha256(b"trans-am")),
+                cert_sha256: None,
+                activity_types: Some(vec!["WJZTX".to_owned()]),
+                tenants: None,
+                admin: false,
+            }],
+        };
+        assert!(producers.authenticate(Some("gn-drive"), None).is_none());
+        assert!(producers.authenticate(None, Some(b"trans-am")).is_none());
+        let his = producers.authenticate(Some("trans-am"), None).unwrap();
+
+        let mut trial = Trial {
+            tenant: "celestial".to_owned(),
+            message: Activity {
+                activity_type: "WJZTX".to_owned(),
+                ..Default::default()
+            },
+            receivers: vec!["gandum".to_owned()],
+        };
+        assert!(his.authorize(&trial).is_ok());
+        trial.message.activity_type = "love".to_owned();
+        assert!(his.authorize(&trial).is_err());
+
+        let anonymous = Authenticator { producers: None };
+        assert!(anonymous.authenticate(None, None).unwrap().is_none());
+        let auth = Authenticator {
+            producers: Some(Arc::new(producers)),
+        };
+        assert!(auth.authenticate(None, None).is_err());
+        assert_eq!(
+            auth.authenticate(Some("trans-am"), None)
+                .unwrap()
+                .unwrap()
+                .name,
+            "his"
+        );
+    }
+}
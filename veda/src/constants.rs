@@ -6,11 +6,45 @@ use std::time::Duration;
 /// max len of redis stream for each key is 1000
 //pub const MAXLEN: StreamMaxlen = StreamMaxlen::Approx(1000);
 
-/// blocking message time milliseconds
-pub const BLOCK_MILLIS: usize = 600;
-/// polling message time interval
+/// default polling message time interval, sessions are also woken up by new activities
 pub const MESSAGE_INTERVAL: Duration = Duration::from_millis(1000);
-/// How often heartbeat pings are sent
+/// default of `long_poll_wait`, also how often abandoned long-poll waiters are dropped
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// wait before subscribing to wake-ups again after the subscription breaks
+pub const WAKE_RESUBSCRIBE: Duration = Duration::from_secs(1);
+/// default of how often heartbeat pings are sent
 pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
-/// How long before lack of client response causes a timeout
+/// default of how long before lack of client response causes a timeout
 pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+/// default max activities pushed to a session at once
+pub const BATCH_SIZE: usize = 10;
+/// max commands sent to redis in one pipeline
+pub const PIPELINE_SIZE: usize = 1000;
+/// max receivers of one message
+pub const MAX_RECEIVERS: usize = 10000;
+/// max bytes of a receiver's name
+pub const MAX_RECEIVER_BYTES: usize = 128;
+/// max bytes of activity content
+pub const MAX_CONTENT_BYTES: usize = 64 * 1024;
+/// max custom headers of an activity
+pub const MAX_HEADERS: usize = 32;
+/// max bytes of a header value
+pub const MAX_HEADER_BYTES: usize = 1024;
+/// how long grpc waits for redis before treating veda as overloaded
+pub const TRIAL_TIMEOUT: Duration = Duration::from_secs(5);
+/// how many times a webhook receipt is posted before giving up
+pub const WEBHOOK_RETRIES: u32 = 5;
+/// wait before the first retry, doubled after each failure
+pub const WEBHOOK_BACKOFF: Duration = Duration::from_secs(1);
+/// timeout of each webhook request
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// how long delivered messages wait for read/ack receipts, in seconds
+pub const CALLBACK_TTL: usize = 7 * 24 * 60 * 60;
+/// longest time a long-poll request waits for activities
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+/// max activities returned by one long-poll request
+pub const POLL_BATCH: usize = 100;
+/// how often certificate files are checked for changes
+pub const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+/// how often activity types registered on other instances are picked up
+pub const REGISTRY_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
@@ -0,0 +1,157 @@
+//! engine.io(v3/v4)和socket.io的报文编解码,只支持websocket transport
+use serde_json::{json, Value};
+
+use crate::config::HeartbeatPolicy;
+
+/// websocket上跑的协议
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// veda自己的`/command`文本协议
+    Veda,
+    /// engine.io,`version`为握手时的`EIO`参数
+    EngineIo { version: u8 },
+}
+
+/// engine.io报文
+#[derive(Debug, PartialEq)]
+pub enum Packet<'a> {
+    Close,
+    Ping(&'a str),
+    Pong(&'a str),
+    Message(SocketIo<'a>),
+    Upgrade,
+    Noop,
+}
+
+/// engine.io message里的socket.io报文
+#[derive(Debug, PartialEq)]
+pub enum SocketIo<'a> {
+    Connect,
+    Disconnect,
+    Event {
+        ack: Option<u64>,
+        name: String,
+        args: Vec<Value>,
+    },
+    /// 其他socket.io报文,veda不处理
+    Other(&'a str),
+}
+
+/// 解析客户端发来的文本帧
+pub fn decode(text: &str) -> Result<Packet<'_>, String> {
+    let mut chars = text.chars();
+    let (kind, data) = (chars.next(), chars.as_str());
+    match kind {
+        Some('1') => Ok(Packet::Close),
+        Some('2') => Ok(Packet::Ping(data)),
+        Some('3') => Ok(Packet::Pong(data)),
+        Some('4') => decode_socket_io(data).map(Packet::Message),
+        Some('5') => Ok(Packet::Upgrade),
+        Some('6') => Ok(Packet::Noop),
+        _ => Err(format!("unknown engine.io packet: {:?}", text)),
+    }
+}
+
+fn decode_socket_io(data: &str) -> Result<SocketIo<'_>, String> {
+    let mut chars = data.chars();
+    let (kind, mut rest) = (chars.next(), chars.as_str());
+    match kind {
+        Some('0') => Ok(SocketIo::Connect),
+        Some('1') => Ok(SocketIo::Disconnect),
+        Some('2') => {
+            // 跳过namespace,`/admin,`
+            if rest.starts_with('/') {
+                rest = rest
+                    .split_once(',')
+                    .map(|(_, rest)| rest)
+                    .unwrap_or_default();
+            }
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let ack = rest[..digits].parse().ok();
+            let mut args: Vec<Value> = serde_json::from_str(&rest[digits..])
+                .map_err(|e| format!("invalid socket.io event: {}", e))?;
+            if args.is_empty() {
+                return Err("socket.io event name is required".to_owned());
+            }
+            match args.remove(0) {
+                Value::String(name) => Ok(SocketIo::Event { ack, name, args }),
+                name => Err(format!("invalid socket.io event name: {}", name)),
+            }
+        }
+        _ => Ok(SocketIo::Other(data)),
+    }
+}
+
+/// 握手报文,连接建立后立即发送
+pub fn open(sid: &str, heartbeat: &HeartbeatPolicy) -> String {
+    format!(
+        "0{}",
+        json!({
+            "sid": sid,
+            "upgrades": [],
+            "pingInterval": heartbeat.interval.as_millis() as u64,
+            "pingTimeout": heartbeat.timeout.as_millis() as u64,
+        })
+    )
+}
+
+/// 连接默认namespace的应答,v4需要带上sid
+pub fn connect(version: u8, sid: &str) -> String {
+    if version >= 4 {
+        format!("40{}", json!({ "sid": sid }))
+    } else {
+        "40".to_owned()
+    }
+}
+
+/// 服务端推送的event,`payload`需要是合法的json
+pub fn event(name: &str, payload: &str) -> String {
+    format!("42[{},{}]", json!(name), payload)
+}
+
+/// 对带ack id的event的应答
+pub fn ack(id: u64) -> String {
+    format!("43{}[]", id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_some_packets() {
+        assert_eq!(decode("2probe"), Ok(Packet::Ping("probe")));
+        assert_eq!(decode("5"), Ok(Packet::Upgrade));
+        assert_eq!(decode("40"), Ok(Packet::Message(SocketIo::Connect)));
+        assert_eq!(
+            decode(r#"42["emit","eyJZU0dIIjoiNzgwMyJ9"]"#),
+            Ok(Packet::Message(SocketIo::Event {
+                ack: None,
+                name: "emit".to_owned(),
+                args: vec![json!("eyJZU0dIIjoiNzgwMyJ9")],
+            }))
+        );
+        assert_eq!(
+            decode(r#"42/veda,7["read","1-0"]"#),
+            Ok(Packet::Message(SocketIo::Event {
+                ack: Some(7),
+                name: "read".to_owned(),
+                args: vec![json!("1-0")],
+            }))
+        );
+        assert!(decode("42[]").is_err());
+        assert!(decode("hello").is_err());
+    }
+
+    #[test]
+    fn encode_an_event() {
+        assert_eq!(
+            event("message", r#"[{"a":1}]"#),
+            r#"42["message",[{"a":1}]]"#
+        );
+        assert_eq!(connect(3, "1"), "40");
+        assert_eq!(connect(4, "1"), r#"40{"sid":"1"}"#);
+    }
+}
@@ -1,10 +1,275 @@
+use std::collections::HashMap;
+
+use chrono::Utc;
 use redis::{FromRedisValue, ToRedisArgs};
-use serde::{Deserialize, Serialize};
-#[derive(Deserialize, Serialize)]
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+use crate::constants::{
+    MAX_CONTENT_BYTES, MAX_HEADERS, MAX_HEADER_BYTES, MAX_RECEIVERS, MAX_RECEIVER_BYTES,
+};
+
+lazy_static! {
+    /// 消息类型只允许字母、数字和`_.-`
+    static ref ACTIVITY_TYPE: Regex = Regex::new(r"^[A-Za-z0-9_.\-]{1,64}$").unwrap();
+    /// 租户和消息类型的规则一样,但可以为空
+    static ref TENANT: Regex = Regex::new(r"^[A-Za-z0-9_.\-]{0,64}$").unwrap();
+    /// redis stream ID,`<毫秒>-<序号>`
+    static ref STREAM_ID: Regex = Regex::new(r"^\d{1,20}-\d{1,20}$").unwrap();
+}
+
+/// 消息正文的格式
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    Text,
+    /// 入队时校验,推送时作为对象嵌入
+    Json,
+    Html,
+    /// `{"@type": type_url, "value": base64}`,和json一样推送
+    Protobuf,
+}
+
+impl Default for ContentType {
+    fn default() -> Self {
+        ContentType::Text
+    }
+}
+
+impl ContentType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentType::Text => "text",
+            ContentType::Json => "json",
+            ContentType::Html => "html",
+            ContentType::Protobuf => "protobuf",
+        }
+    }
+
+    pub fn parse(content_type: &str) -> Option<Self> {
+        match content_type {
+            "text" => Some(ContentType::Text),
+            "json" => Some(ContentType::Json),
+            "html" => Some(ContentType::Html),
+            "protobuf" => Some(ContentType::Protobuf),
+            _ => None,
+        }
+    }
+
+    /// 正文是json,推送时不再转义成字符串
+    pub fn is_json(&self) -> bool {
+        matches!(self, ContentType::Json | ContentType::Protobuf)
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Validate)]
+#[validate(schema(function = "validate_typed_content", skip_on_field_errors = false))]
 pub struct Activity {
+    /// 推送时为消息在队列里的ID,客户端重连时用来续传
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     /// event message
+    #[validate(regex(path = "ACTIVITY_TYPE", code = "activity_type"))]
     pub activity_type: String,
+    /// 正文格式,默认为text
+    #[serde(default)]
+    pub content_type: ContentType,
+    /// json正文可以直接传对象,使用模板时可以为空
+    #[serde(default, deserialize_with = "deserialize_content")]
+    #[validate(custom = "validate_content")]
     pub activity: String,
+    /// 消息ID,同一条消息的所有接收者都一样,客户端用来去重
+    #[serde(skip_deserializing)]
+    pub message_id: String,
+    /// 入队时间,毫秒
+    #[serde(skip_deserializing)]
+    pub created_at: i64,
+    /// 生产者身份
+    #[serde(skip_deserializing)]
+    pub producer: String,
+    /// 过期时间,毫秒,由消息类型的ttl决定
+    #[serde(skip_deserializing)]
+    pub expire_at: Option<i64>,
+    /// 优先级,由消息类型决定
+    #[serde(skip_deserializing)]
+    pub priority: i32,
+    /// 正文为空时,用来渲染消息类型的模板
+    #[serde(default)]
+    pub variables: HashMap<String, Value>,
+    /// 模板的语言
+    #[serde(default)]
+    pub locale: String,
+    /// 生产者自定义的header
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    #[validate(custom = "validate_headers")]
+    pub headers: HashMap<String, String>,
+    /// 生产者接收回执的url,不推送给客户端
+    #[serde(skip)]
+    #[validate(url)]
+    pub callback: Option<String>,
+}
+
+/// 推送给客户端的格式,json正文作为对象嵌入
+#[derive(Serialize)]
+struct Pushed<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: &'a Option<String>,
+    activity_type: &'a str,
+    content_type: ContentType,
+    activity: Content<'a>,
+    message_id: &'a str,
+    created_at: i64,
+    producer: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expire_at: Option<i64>,
+    priority: i32,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    headers: &'a HashMap<String, String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Content<'a> {
+    Text(&'a str),
+    Json(Value),
+}
+
+impl Serialize for Activity {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let activity = match self.content_type.is_json() {
+            true => serde_json::from_str(&self.activity)
+                .map(Content::Json)
+                .unwrap_or(Content::Text(&self.activity)),
+            false => Content::Text(&self.activity),
+        };
+        Pushed {
+            id: &self.id,
+            activity_type: &self.activity_type,
+            content_type: self.content_type,
+            activity,
+            message_id: &self.message_id,
+            created_at: self.created_at,
+            producer: &self.producer,
+            expire_at: self.expire_at,
+            priority: self.priority,
+            headers: &self.headers,
+        }
+        .serialize(serializer)
+    }
+}
+
+fn deserialize_content<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    match Value::deserialize(deserializer)? {
+        Value::String(content) => Ok(content),
+        content => Ok(content.to_string()),
+    }
+}
+
+fn validate_typed_content(activity: &Activity) -> Result<(), ValidationError> {
+    match activity.content_type {
+        ContentType::Json => serde_json::from_str::<Value>(&activity.activity)
+            .map(|_| ())
+            .map_err(|_| ValidationError::new("invalid_json")),
+        ContentType::Protobuf => match serde_json::from_str::<Value>(&activity.activity) {
+            Ok(any) if any["@type"].is_string() && any["value"].is_string() => Ok(()),
+            _ => Err(ValidationError::new("invalid_any")),
+        },
+        ContentType::Text | ContentType::Html => Ok(()),
+    }
+}
+
+fn validate_content(content: &str) -> Result<(), ValidationError> {
+    if content.is_empty() {
+        return Err(ValidationError::new("content_required"));
+    }
+    if content.len() > MAX_CONTENT_BYTES {
+        return Err(ValidationError::new("content_too_large"));
+    }
+    Ok(())
+}
+
+fn validate_headers(headers: &HashMap<String, String>) -> Result<(), ValidationError> {
+    if headers.len() > MAX_HEADERS {
+        return Err(ValidationError::new("too_many_headers"));
+    }
+    for (key, value) in headers {
+        if !ACTIVITY_TYPE.is_match(key) {
+            return Err(ValidationError::new("header_key"));
+        }
+        if value.len() > MAX_HEADER_BYTES {
+            return Err(ValidationError::new("header_too_large"));
+        }
+    }
+    Ok(())
+}
+
+impl Activity {
+    /// 已经过了消息类型的ttl,不再推送
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expire_at.map_or(false, |expire_at| expire_at <= now)
+    }
+
+    /// 入队前由veda填写消息ID、时间和生产者
+    pub fn stamp(&mut self, producer: &str) {
+        self.message_id = Uuid::new_v4().to_string();
+        self.created_at = Utc::now().timestamp_millis();
+        self.producer = producer.to_owned();
+    }
+}
+
+pub fn validate_stream_id(id: &str) -> Result<(), ValidationError> {
+    if STREAM_ID.is_match(id) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("stream_id"))
+    }
+}
+
+pub fn validate_activity_type(activity_type: &str) -> Result<(), ValidationError> {
+    if ACTIVITY_TYPE.is_match(activity_type) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("activity_type"))
+    }
+}
+
+pub fn validate_tenant(tenant: &str) -> Result<(), ValidationError> {
+    if TENANT.is_match(tenant) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("invalid_tenant"))
+    }
+}
+
+/// 用户名不能为空,长度有上限,不能有空白字符和redis key的分隔符`:`
+pub fn validate_username(username: &str) -> Result<(), ValidationError> {
+    if username.is_empty()
+        || username.len() > MAX_RECEIVER_BYTES
+        || username.chars().any(|c| c.is_whitespace() || c == ':')
+    {
+        return Err(ValidationError::new("invalid_username"));
+    }
+    Ok(())
+}
+
+/// 接收者不能为空,数量有上限,每个都是合法的用户名
+pub fn validate_receivers(receivers: &[String]) -> Result<(), ValidationError> {
+    if receivers.is_empty() {
+        return Err(ValidationError::new("receivers_required"));
+    }
+    if receivers.len() > MAX_RECEIVERS {
+        return Err(ValidationError::new("too_many_receivers"));
+    }
+    if receivers
+        .iter()
+        .any(|receiver| validate_username(receiver).is_err())
+    {
+        return Err(ValidationError::new("invalid_receiver"));
+    }
+    Ok(())
 }
 
 impl ToRedisArgs for &Activity {
@@ -14,8 +279,32 @@ impl ToRedisArgs for &Activity {
     {
         "activity_type".write_redis_args(out);
         self.activity_type.write_redis_args(out);
+        "content_type".write_redis_args(out);
+        self.content_type.as_str().write_redis_args(out);
         "activity".write_redis_args(out);
         self.activity.write_redis_args(out);
+        "message_id".write_redis_args(out);
+        self.message_id.write_redis_args(out);
+        "created_at".write_redis_args(out);
+        self.created_at.write_redis_args(out);
+        "producer".write_redis_args(out);
+        self.producer.write_redis_args(out);
+        if let Some(expire_at) = self.expire_at {
+            "expire_at".write_redis_args(out);
+            expire_at.write_redis_args(out);
+        }
+        "priority".write_redis_args(out);
+        self.priority.write_redis_args(out);
+        if !self.headers.is_empty() {
+            if let Ok(headers) = serde_json::to_string(&self.headers) {
+                "headers".write_redis_args(out);
+                headers.write_redis_args(out);
+            }
+        }
+        if let Some(callback) = &self.callback {
+            "callback".write_redis_args(out);
+            callback.write_redis_args(out);
+        }
     }
 }
 
@@ -41,3 +330,70 @@ impl FromRedisValue for Activity {
             .collect())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn activity(activity_type: &str, activity: &str) -> Activity {
+        Activity {
+            activity_type: activity_type.to_string(),
+            activity: activity.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn validate_an_activity() {
+        assert!(activity("event", "{}").validate().is_ok());
+        assert!(activity("", "{}").validate().is_err());
+        assert!(activity("event type", "{}").validate().is_err());
+        assert!(activity("event", "").validate().is_err());
+        assert!(activity("event", &"x".repeat(MAX_CONTENT_BYTES + 1))
+            .validate()
+            .is_err());
+
+        let mut with_callback = activity("event", "{}");
+        with_callback.callback = Some("not a url".to_string());
+        assert!(with_callback.validate().is_err());
+        with_callback.callback = Some("https://example.com/receipts".to_string());
+        assert!(with_callback.validate().is_ok());
+
+        let mut with_headers = activity("event", "{}");
+        with_headers
+            .headers
+            .insert("trace-id".to_string(), "0af7651916cd43dd".to_string());
+        assert!(with_headers.validate().is_ok());
+        with_headers
+            .headers
+            .insert("trace id".to_string(), "0af7651916cd43dd".to_string());
+        assert!(with_headers.validate().is_err());
+    }
+
+    #[test]
+    fn push_json_as_an_object() {
+        let mut json = activity("event", r#"{"subject":"Allen"}"#);
+        json.content_type = ContentType::Json;
+        assert!(json.validate().is_ok());
+        let pushed = serde_json::to_value(&json).unwrap();
+        assert_eq!(pushed["activity"]["subject"], "Allen");
+
+        json.activity = "Allen".to_string();
+        assert!(json.validate().is_err());
+
+        let text: Activity =
+            serde_json::from_str(r#"{"activity_type":"event","activity":"Allen"}"#).unwrap();
+        assert_eq!(text.content_type, ContentType::Text);
+        let pushed = serde_json::to_value(&text).unwrap();
+        assert_eq!(pushed["activity"], "Allen");
+    }
+
+    #[test]
+    fn validate_some_receivers() {
+        assert!(validate_receivers(&["gandum".to_string(), "00".to_string()]).is_ok());
+        assert!(validate_receivers(&[]).is_err());
+        assert!(validate_receivers(&["".to_string()]).is_err());
+        assert!(validate_receivers(&["gan dum".to_string()]).is_err());
+        assert!(validate_receivers(&["gan:dum".to_string()]).is_err());
+    }
+}
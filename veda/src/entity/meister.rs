@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use validator::Validate;
+
+use super::{validate_tenant, validate_username};
+
+/// Gandum meister, 租户内唯一的用户
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize, Validate)]
+pub struct Meister {
+    /// 租户(医疗机构代码),为空时是全局用户
+    #[validate(custom = "validate_tenant")]
+    pub tenant: String,
+    /// identity
+    #[validate(custom = "validate_username")]
+    pub username: String,
+}
+
+impl Meister {
+    pub fn new(tenant: &str, username: &str) -> Self {
+        Self {
+            tenant: tenant.to_owned(),
+            username: username.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for Meister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.tenant.is_empty() {
+            write!(f, "{}", self.username)
+        } else {
+            write!(f, "{}@{}", self.username, self.tenant)
+        }
+    }
+}
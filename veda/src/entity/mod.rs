@@ -1,3 +1,7 @@
 mod activity;
+mod meister;
+mod parameter;
 mod platform;
-pub use self::{activity::*, platform::*};
+mod receipt;
+mod session;
+pub use self::{activity::*, meister::*, parameter::*, platform::*, receipt::*, session::*};
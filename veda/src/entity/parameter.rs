@@ -0,0 +1,30 @@
+use serde::Deserialize;
+
+use super::Meister;
+
+/// 老的socket.io前端通过`emit`事件发送的参数,base64编码的json
+#[derive(Debug, Deserialize)]
+pub struct Parameter {
+    /// 机构编码,对应租户
+    #[serde(rename = "YLJGDM", default)]
+    pub jgdm: String,
+
+    /// 医生工号,对应用户名
+    #[serde(rename = "YSGH", default)]
+    pub ysgh: String,
+
+    /// `login`或`offilneMsg`
+    #[serde(rename = "METHOD", default)]
+    pub method: String,
+}
+
+impl Parameter {
+    pub fn decode(b64: &str) -> Result<Self, String> {
+        let json = base64::decode(b64.trim()).map_err(|e| format!("invalid base64: {}", e))?;
+        serde_json::from_slice(&json).map_err(|e| format!("invalid parameter: {}", e))
+    }
+
+    pub fn meister(&self) -> Meister {
+        Meister::new(&self.jgdm, &self.ysgh)
+    }
+}
@@ -57,21 +57,40 @@ pub enum Platform {
     Windows(Info),
 }
 
+impl Platform {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Platform::Android(_) => "Android",
+            Platform::Embedded(_) => "Embedded",
+            Platform::IPhone(_) => "IPhone",
+            Platform::IPad(_) => "IPad",
+            Platform::Macos(_) => "Macos",
+            Platform::Tablet(_) => "Tablet",
+            Platform::Web(_) => "Web",
+            Platform::Windows(_) => "Windows",
+        }
+    }
+
+    pub fn device(&self) -> &Info {
+        match self {
+            Platform::Android(info)
+            | Platform::Embedded(info)
+            | Platform::IPhone(info)
+            | Platform::IPad(info)
+            | Platform::Macos(info)
+            | Platform::Tablet(info)
+            | Platform::Web(info)
+            | Platform::Windows(info) => info,
+        }
+    }
+}
+
 impl ToRedisArgs for Platform {
     fn write_redis_args<W>(&self, out: &mut W)
     where
         W: ?Sized + redis::RedisWrite,
     {
-        let (platform, device) = match self {
-            Platform::Android(info) => ("Android", info),
-            Platform::Embedded(info) => ("Embedded", info),
-            Platform::IPhone(info) => ("IPhone", info),
-            Platform::IPad(info) => ("IPad", info),
-            Platform::Macos(info) => ("Macos", info),
-            Platform::Tablet(info) => ("Tablet", info),
-            Platform::Web(info) => ("Web", info),
-            Platform::Windows(info) => ("Windows", info),
-        };
+        let (platform, device) = (self.name(), self.device());
 
         out.write_arg(b"platform");
         out.write_arg(platform.as_bytes());
@@ -96,51 +115,6 @@ impl FromRedisValue for Platform {
     }
 }
 
-// /// Gandum meister
-// #[derive(Deserialize, Serialize)]
-// pub struct Meister {
-//     /// identity
-//     pub username: String,
-//     // token: Vec<String>,
-//     // tags: Vec<String>,
-//     pub sessions: HashMap<usize, Platform>,
-// }
-
-// impl FromRedisValue for Meister {
-//     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
-//         match *v {
-//             redis::Value::Data(ref val) => match serde_json::from_slice(val) {
-//                 Err(_) => Err(((redis::ErrorKind::TypeError, "Can't serialize value")).into()),
-//                 Ok(v) => Ok(v),
-//             },
-//             _ => Err(((
-//                 redis::ErrorKind::ResponseError,
-//                 "Response type not Dashboard compatible.",
-//             ))
-//                 .into()),
-//         }
-//     }
-// }
-
-// impl ToRedisArgs for Meister {
-//     fn write_redis_args<W>(&self, out: &mut W)
-//     where
-//         W: ?Sized + redis::RedisWrite,
-//     {
-//         out.write_arg(b"username");
-//         out.write_arg(self.username.as_bytes());
-//         out.write_arg(b"sessions");
-//         out.write_arg(&self.sessions);
-//         // self.sessions.write_redis_args(out);
-//     }
-// }
-
-// impl FromRedisValue for Meister {
-//     fn from_redis_value(v: &redis::Value) -> redis::RedisResult<Self> {
-//         let
-//     }
-// }
-
 // /// 存储标签和Meister的关系
 // pub struct Halo {
 //     pub halo: String,
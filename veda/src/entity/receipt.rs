@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+/// 回执事件
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryEvent {
+    /// 消息已推送到客户端
+    Delivered,
+    /// 客户端已阅读
+    Read,
+    /// 客户端已确认
+    Acked,
+}
+
+/// 推送给生产者webhook的消息回执
+#[derive(Serialize)]
+pub struct DeliveryReceipt {
+    /// 生产者看到的消息ID,即`Activity.message_id`
+    pub message_id: String,
+    pub tenant: String,
+    pub receiver: String,
+    pub activity_type: String,
+    pub event: DeliveryEvent,
+    /// 事件时间
+    pub at: i64,
+}
+
+/// 已推送消息的回调地址,等待客户端阅读和确认
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Subscription {
+    pub url: String,
+    pub activity_type: String,
+    #[serde(default)]
+    pub message_id: String,
+}
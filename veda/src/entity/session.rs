@@ -0,0 +1,33 @@
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::Meister;
+
+/// session ID,uuid v4,不同实例之间也不会重复
+pub type SessionId = Uuid;
+
+/// 建立连接时客户端的信息
+#[derive(Clone, Debug, Default)]
+pub struct Remote {
+    /// 客户端地址,经过代理时取`X-Forwarded-For`
+    pub addr: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// 在线session的登记信息,admin接口查询
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    /// `websocket`,`socket.io`或`sse`
+    pub transport: &'static str,
+    /// 建立连接的时间,毫秒
+    pub connected_at: i64,
+    pub remote_addr: Option<String>,
+    pub user_agent: Option<String>,
+    /// 登录后才有
+    #[serde(flatten)]
+    pub meister: Option<Meister>,
+    pub platform: Option<String>,
+    /// 最后一次收到客户端心跳的时间,毫秒
+    pub last_heartbeat: i64,
+}
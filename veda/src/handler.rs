@@ -1,38 +1,555 @@
-use crate::addr::{Redis, Seravee, Websocket, WebsocketSession};
-use actix::Addr;
+use crate::{
+    addr::{
+        ActivityKind, Broadcast, Classify, ClassifyBroadcast, Kick, Limiter, ListKinds, ListNames,
+        ListSessions, Poll, Redis, Register, Registry, SessionState, SseSession, Throttle, Trial,
+        Unregister, Watch, Websocket, WebsocketSession,
+    },
+    auth::{Authenticator, Producer},
+    config::CONFIG,
+    constants::{POLL_BATCH, POLL_TIMEOUT, TRIAL_TIMEOUT},
+    eio::Protocol,
+    entity::{validate_stream_id, Activity, Meister, Remote, SessionId},
+};
+use actix::{Actor, Addr};
 use actix_web::{
-    web::{self},
+    error::{
+        ErrorBadRequest, ErrorForbidden, ErrorServiceUnavailable, ErrorTooManyRequests,
+        ErrorUnauthorized,
+    },
+    web::{self, Json},
     Error, HttpRequest, HttpResponse,
 };
 use actix_web_actors::ws;
-use std::time::Instant;
+use serde::Deserialize;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use validator::Validate;
+
+/// 客户端地址和User-Agent,登记到session信息里
+fn remote(req: &HttpRequest) -> Remote {
+    Remote {
+        addr: req
+            .connection_info()
+            .realip_remote_addr()
+            .map(str::to_owned),
+        user_agent: req
+            .headers()
+            .get("user-agent")
+            .and_then(|agent| agent.to_str().ok())
+            .map(str::to_owned),
+    }
+}
 
 pub async fn socket_route(
     req: HttpRequest,
     stream: web::Payload,
-    grpc_addr: web::Data<Addr<Seravee>>,
     redis_addr: web::Data<Addr<Redis>>,
     srv: web::Data<Addr<Websocket>>,
 ) -> Result<HttpResponse, Error> {
     ws::start(
         WebsocketSession {
-            id: 0,
-            name: None,
+            id: SessionId::nil(),
+            state: SessionState::Connecting(Vec::new()),
+            remote: remote(&req),
+            hb: Instant::now(),
+            heartbeat: CONFIG.heartbeat(None),
+            hb_handle: None,
+            protocol: Protocol::Veda,
+            redis_addr: redis_addr.get_ref().clone(),
+            websocket_addr: srv.get_ref().clone(),
+        },
+        &req,
+        stream,
+    )
+}
+
+/// engine.io握手参数
+#[derive(Deserialize)]
+pub struct EngineIoQuery {
+    #[serde(rename = "EIO", default = "default_eio")]
+    pub eio: u8,
+    #[serde(default)]
+    pub transport: String,
+}
+
+fn default_eio() -> u8 {
+    3
+}
+
+/// 兼容socket.io客户端,只支持websocket transport
+pub async fn socket_io_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    query: web::Query<EngineIoQuery>,
+    redis_addr: web::Data<Addr<Redis>>,
+    srv: web::Data<Addr<Websocket>>,
+) -> Result<HttpResponse, Error> {
+    if query.transport != "websocket" {
+        return Err(ErrorBadRequest("only websocket transport is supported"));
+    }
+    if !(3..=4).contains(&query.eio) {
+        return Err(ErrorBadRequest("unsupported engine.io version"));
+    }
+
+    ws::start(
+        WebsocketSession {
+            id: SessionId::nil(),
+            state: SessionState::Connecting(Vec::new()),
+            remote: remote(&req),
             hb: Instant::now(),
+            heartbeat: CONFIG.heartbeat(None),
+            hb_handle: None,
+            protocol: Protocol::EngineIo { version: query.eio },
             redis_addr: redis_addr.get_ref().clone(),
             websocket_addr: srv.get_ref().clone(),
-            grpc_addr: grpc_addr.get_ref().clone(),
         },
         &req,
         stream,
     )
 }
 
-// pub async fn push_msg_route(
-//     msg: Json<PushMessage>,
-//     redis_addr: web::Data<Addr<Redis>>,
-//     srv: web::Data<Addr<Websocket>>,
-// ) -> Result<MessageAction, Error> {
-//     let ws_addr = srv.as_ref();
-//     ws_addr.send(msg)
-// }
+/// Server-Sent Events登录参数
+#[derive(Deserialize)]
+pub struct SseQuery {
+    pub name: String,
+    #[serde(default)]
+    pub tenant: String,
+}
+
+/// 给不能升级websocket的客户端使用,断线重连时根据`Last-Event-ID`续传
+pub async fn sse_route(
+    req: HttpRequest,
+    query: web::Query<SseQuery>,
+    redis_addr: web::Data<Addr<Redis>>,
+    srv: web::Data<Addr<Websocket>>,
+) -> Result<HttpResponse, Error> {
+    let SseQuery { name, tenant } = query.into_inner();
+    let meister = Meister::new(&tenant, &name);
+    meister.validate().map_err(ErrorBadRequest)?;
+
+    let cursor = match req.headers().get("last-event-id") {
+        Some(id) => {
+            let id = id.to_str().map_err(ErrorBadRequest)?;
+            validate_stream_id(id).map_err(ErrorBadRequest)?;
+            Some(id.to_owned())
+        }
+        None => None,
+    };
+
+    let (session, body) = SseSession::new(
+        meister,
+        remote(&req),
+        cursor,
+        redis_addr.get_ref().clone(),
+        srv.get_ref().clone(),
+    );
+    session.start();
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        // 关闭nginx的缓冲
+        .insert_header(("X-Accel-Buffering", "no"))
+        .streaming(body))
+}
+
+/// 长轮询参数
+#[derive(Deserialize)]
+pub struct PollQuery {
+    pub name: String,
+    #[serde(default)]
+    pub tenant: String,
+    /// 上一次返回的`cursor`,同时确认之前的消息已经收到
+    pub cursor: Option<String>,
+    /// 最长等待秒数,不超过`POLL_TIMEOUT`
+    pub timeout: Option<u64>,
+}
+
+/// 给只能发http请求的设备使用,没有消息时等到超时再返回
+pub async fn poll_route(
+    query: web::Query<PollQuery>,
+    redis_addr: web::Data<Addr<Redis>>,
+) -> Result<HttpResponse, Error> {
+    let PollQuery {
+        name,
+        tenant,
+        cursor,
+        timeout,
+    } = query.into_inner();
+    let meister = Meister::new(&tenant, &name);
+    meister.validate().map_err(ErrorBadRequest)?;
+    if let Some(cursor) = &cursor {
+        validate_stream_id(cursor).map_err(ErrorBadRequest)?;
+    }
+
+    let timeout = timeout
+        .map(Duration::from_secs)
+        .map_or(POLL_TIMEOUT, |timeout| timeout.min(POLL_TIMEOUT));
+    let deadline = Instant::now() + timeout;
+    let mut cursor = cursor;
+
+    let activities = loop {
+        // watch before reading, so activities queued in between still wake us up
+        let woken = redis_addr
+            .send(Watch {
+                meister: meister.clone(),
+            })
+            .await
+            .map_err(ErrorServiceUnavailable)?;
+        let polled = redis_addr
+            .send(Poll {
+                meister: meister.clone(),
+                cursor: cursor.clone(),
+                count: POLL_BATCH,
+            })
+            .await
+            .map_err(ErrorServiceUnavailable)?
+            .map_err(ErrorServiceUnavailable)?;
+        // expired activities are dropped but still move the cursor
+        if polled.cursor.is_some() {
+            cursor = polled.cursor;
+        }
+        let now = Instant::now();
+        if !polled.activities.is_empty() || now >= deadline {
+            break polled.activities;
+        }
+        // read again once woken up, or after `long_poll_wait` in case the wake-up is lost
+        let _ = actix_web::rt::time::timeout((deadline - now).min(CONFIG.long_poll_wait()), woken)
+            .await;
+    };
+
+    let cursor = cursor.unwrap_or_else(|| "0".to_owned());
+    Ok(HttpResponse::Ok().json(json!({
+        "cursor": cursor,
+        "activities": activities,
+    })))
+}
+
+/// 通过http推送的消息
+#[derive(Deserialize)]
+pub struct PushMessage {
+    /// 接收者所在的租户
+    #[serde(default)]
+    pub tenant: String,
+    pub receivers: Vec<String>,
+    #[serde(flatten)]
+    pub message: Activity,
+    /// 接收回执的url
+    pub callback: Option<String>,
+}
+
+/// 用`x-veda-api-key`认证生产者,http不要求客户端证书.
+/// 没有登记生产者时为`None`
+fn authenticate(req: &HttpRequest, auth: &Authenticator) -> Result<Option<Producer>, Error> {
+    let api_key = req
+        .headers()
+        .get("x-veda-api-key")
+        .and_then(|key| key.to_str().ok());
+    auth.authenticate(api_key, None).map_err(ErrorUnauthorized)
+}
+
+/// 管理接口只开放给认证过的管理员生产者,没有登记生产者时不开放
+fn admin(req: &HttpRequest, auth: &Authenticator) -> Result<Producer, Error> {
+    match authenticate(req, auth)? {
+        Some(producer) if producer.admin => Ok(producer),
+        Some(producer) => Err(ErrorForbidden(format!(
+            "producer `{}` is not an admin",
+            &producer.name
+        ))),
+        None => Err(ErrorForbidden("admin routes require `producers`")),
+    }
+}
+
+/// 生产者身份,认证过时使用登记的名字,否则使用对端地址.
+/// 客户端自己声明的名字不能作为限流的依据
+fn producer(req: &HttpRequest, authenticated: Option<&Producer>) -> String {
+    if let Some(producer) = authenticated {
+        return producer.name.clone();
+    }
+    req.peer_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default()
+}
+
+pub async fn push_msg_route(
+    req: HttpRequest,
+    msg: Json<PushMessage>,
+    redis_addr: web::Data<Addr<Redis>>,
+    limiter_addr: web::Data<Addr<Limiter>>,
+    registry_addr: web::Data<Addr<Registry>>,
+    auth: web::Data<Authenticator>,
+) -> Result<HttpResponse, Error> {
+    let authenticated = authenticate(&req, &auth)?;
+    let PushMessage {
+        tenant,
+        receivers,
+        mut message,
+        callback,
+    } = msg.into_inner();
+    let producer = producer(&req, authenticated.as_ref());
+    message.callback = callback;
+    message.stamp(&producer);
+    let trial = Trial {
+        tenant,
+        message,
+        receivers,
+    };
+    if let Some(authenticated) = &authenticated {
+        authenticated.authorize(&trial).map_err(ErrorForbidden)?;
+    }
+    let trial = registry_addr
+        .send(Classify {
+            trials: vec![trial],
+        })
+        .await
+        .map_err(ErrorServiceUnavailable)?
+        .pop()
+        .expect("classify keeps every trial")
+        .map_err(ErrorBadRequest)?;
+
+    limiter_addr
+        .send(Throttle::new(producer, 1, trial.receivers()))
+        .await
+        .map_err(ErrorServiceUnavailable)?
+        .map_err(ErrorTooManyRequests)?;
+
+    let receipts = redis_addr
+        .send(trial)
+        .timeout(TRIAL_TIMEOUT)
+        .await
+        .map_err(ErrorServiceUnavailable)?
+        .map_err(ErrorServiceUnavailable)?;
+
+    let states: Vec<_> = receipts
+        .into_iter()
+        .map(|receipt| match receipt.queued {
+            Ok(id) => json!({
+                "receiver": receipt.receiver,
+                "message": id,
+                "devices": receipt.devices,
+            }),
+            // `retry` is set when the failure is transient, such as a broken connection
+            Err(reason) => json!({
+                "receiver": receipt.receiver,
+                "reason": reason,
+                "retry": receipt.transient,
+                "devices": receipt.devices,
+            }),
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(states))
+}
+
+/// 广播给租户内所有在线session的消息,不写入stream
+#[derive(Deserialize)]
+pub struct BroadcastMessage {
+    #[serde(default)]
+    pub tenant: String,
+    #[serde(flatten)]
+    pub message: Activity,
+}
+
+/// 和`/push`一样认证,校验和限流,只占用生产者的令牌
+pub async fn broadcast_route(
+    req: HttpRequest,
+    msg: Json<BroadcastMessage>,
+    srv: web::Data<Addr<Websocket>>,
+    limiter_addr: web::Data<Addr<Limiter>>,
+    registry_addr: web::Data<Addr<Registry>>,
+    auth: web::Data<Authenticator>,
+) -> Result<HttpResponse, Error> {
+    let authenticated = authenticate(&req, &auth)?;
+    let BroadcastMessage {
+        tenant,
+        mut message,
+    } = msg.into_inner();
+    let producer = producer(&req, authenticated.as_ref());
+    message.stamp(&producer);
+    if let Some(authenticated) = &authenticated {
+        authenticated
+            .permit(&message.activity_type, &tenant)
+            .map_err(ErrorForbidden)?;
+    }
+    let message = registry_addr
+        .send(ClassifyBroadcast {
+            tenant: tenant.clone(),
+            message,
+        })
+        .await
+        .map_err(ErrorServiceUnavailable)?
+        .map_err(ErrorBadRequest)?;
+
+    limiter_addr
+        .send(Throttle::new(producer, 1, std::iter::empty()))
+        .await
+        .map_err(ErrorServiceUnavailable)?
+        .map_err(ErrorTooManyRequests)?;
+
+    let sessions = srv
+        .send(Broadcast {
+            tenant,
+            msg: serde_json::to_string(&[message])?,
+        })
+        .await
+        .map_err(ErrorServiceUnavailable)?;
+    Ok(HttpResponse::Ok().json(json!({ "sessions": sessions })))
+}
+
+#[derive(Deserialize)]
+pub struct TenantQuery {
+    #[serde(default)]
+    pub tenant: String,
+}
+
+/// 只能查询管理员可以管理的租户
+pub async fn online_route(
+    req: HttpRequest,
+    query: web::Query<TenantQuery>,
+    srv: web::Data<Addr<Websocket>>,
+    auth: web::Data<Authenticator>,
+) -> Result<HttpResponse, Error> {
+    let admin = admin(&req, &auth)?;
+    let tenant = query.into_inner().tenant;
+    if !admin.allows_tenant(&tenant) {
+        return Err(ErrorForbidden(format!(
+            "producer `{}` can't list users of tenant `{}`",
+            &admin.name, &tenant
+        )));
+    }
+    let names = srv
+        .send(ListNames { tenant })
+        .await
+        .map_err(ErrorServiceUnavailable)?;
+    Ok(HttpResponse::Ok().json(names))
+}
+
+/// 在线session查询参数,不指定时列出所有session
+#[derive(Deserialize)]
+pub struct SessionQuery {
+    pub tenant: Option<String>,
+    pub username: Option<String>,
+}
+
+/// 只能看到管理员可以管理的租户的session
+pub async fn list_sessions_route(
+    req: HttpRequest,
+    query: web::Query<SessionQuery>,
+    srv: web::Data<Addr<Websocket>>,
+    auth: web::Data<Authenticator>,
+) -> Result<HttpResponse, Error> {
+    let admin = admin(&req, &auth)?;
+    let SessionQuery { tenant, username } = query.into_inner();
+    let mut sessions = srv
+        .send(ListSessions { tenant, username })
+        .await
+        .map_err(ErrorServiceUnavailable)?;
+    sessions.retain(|session| match &session.meister {
+        Some(meister) => admin.allows_tenant(&meister.tenant),
+        None => admin.tenants.is_none(),
+    });
+    Ok(HttpResponse::Ok().json(sessions))
+}
+
+/// 管理员能不能修改这个消息类型
+fn manage_kind(admin: &Producer, activity_type: &str) -> Result<(), Error> {
+    if admin.allows_activity_type(activity_type) {
+        Ok(())
+    } else {
+        Err(ErrorForbidden(format!(
+            "producer `{}` can't manage `{}`",
+            &admin.name, activity_type
+        )))
+    }
+}
+
+pub async fn list_kinds_route(
+    req: HttpRequest,
+    registry_addr: web::Data<Addr<Registry>>,
+    auth: web::Data<Authenticator>,
+) -> Result<HttpResponse, Error> {
+    admin(&req, &auth)?;
+    let kinds = registry_addr
+        .send(ListKinds)
+        .await
+        .map_err(ErrorServiceUnavailable)?;
+    Ok(HttpResponse::Ok().json(kinds))
+}
+
+/// 登记或更新消息类型,路径里的`activity_type`优先
+pub async fn register_kind_route(
+    req: HttpRequest,
+    activity_type: web::Path<String>,
+    kind: Json<ActivityKind>,
+    registry_addr: web::Data<Addr<Registry>>,
+    auth: web::Data<Authenticator>,
+) -> Result<HttpResponse, Error> {
+    let mut kind = kind.into_inner();
+    kind.activity_type = activity_type.into_inner();
+    manage_kind(&admin(&req, &auth)?, &kind.activity_type)?;
+    registry_addr
+        .send(Register { kind: kind.clone() })
+        .await
+        .map_err(ErrorServiceUnavailable)?
+        .map_err(ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(kind))
+}
+
+pub async fn unregister_kind_route(
+    req: HttpRequest,
+    activity_type: web::Path<String>,
+    registry_addr: web::Data<Addr<Registry>>,
+    auth: web::Data<Authenticator>,
+) -> Result<HttpResponse, Error> {
+    let activity_type = activity_type.into_inner();
+    manage_kind(&admin(&req, &auth)?, &activity_type)?;
+    let existed = registry_addr
+        .send(Unregister { activity_type })
+        .await
+        .map_err(ErrorServiceUnavailable)?
+        .map_err(ErrorServiceUnavailable)?;
+    if existed {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::NotFound().finish())
+    }
+}
+
+/// 管理员关闭session,空字段表示不指定
+#[derive(Deserialize)]
+pub struct KickRequest {
+    #[serde(default)]
+    pub session: String,
+    #[serde(default)]
+    pub tenant: String,
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub platform: String,
+    #[serde(default)]
+    pub reason: String,
+}
+
+pub async fn kick_route(
+    req: HttpRequest,
+    kick: Json<KickRequest>,
+    srv: web::Data<Addr<Websocket>>,
+    auth: web::Data<Authenticator>,
+) -> Result<HttpResponse, Error> {
+    let admin = admin(&req, &auth)?;
+    if !admin.allows_tenant(&kick.tenant) {
+        return Err(ErrorForbidden(format!(
+            "producer `{}` can't kick sessions of tenant `{}`",
+            &admin.name, &kick.tenant
+        )));
+    }
+    let kick = Kick::new(
+        &kick.session,
+        &kick.tenant,
+        &kick.username,
+        &kick.platform,
+        &kick.reason,
+    )
+    .map_err(ErrorBadRequest)?;
+    let sessions = srv.send(kick).await.map_err(ErrorServiceUnavailable)?;
+    Ok(HttpResponse::Ok().json(json!({ "sessions": sessions })))
+}
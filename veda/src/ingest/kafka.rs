@@ -0,0 +1,58 @@
+use rdkafka::{
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    ClientConfig, Message, Offset, TopicPartitionList,
+};
+
+use super::{Position, Record, Source};
+
+/// kafka consumer group,关闭自动提交,入队后再提交
+pub struct KafkaSource {
+    consumer: StreamConsumer,
+}
+
+impl KafkaSource {
+    /// `brokers`为逗号分隔的`host:port`
+    pub fn new(brokers: &str, topic: &str, group: &str) -> Result<Self, String> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", group)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .map_err(|e| format!("kafka {}: {}", brokers, e))?;
+        consumer
+            .subscribe(&[topic])
+            .map_err(|e| format!("kafka topic {}: {}", topic, e))?;
+        Ok(Self { consumer })
+    }
+}
+
+#[tonic::async_trait(?Send)]
+impl Source for KafkaSource {
+    async fn recv(&mut self) -> Result<Option<Record>, String> {
+        let message = self.consumer.recv().await.map_err(|e| e.to_string())?;
+        Ok(Some(Record {
+            position: Position {
+                topic: message.topic().to_owned(),
+                partition: message.partition(),
+                offset: message.offset(),
+            },
+            payload: message.payload().unwrap_or_default().to_vec(),
+        }))
+    }
+
+    /// kafka提交的是下一条要消费的offset
+    async fn commit(&mut self, position: &Position) -> Result<(), String> {
+        let mut offsets = TopicPartitionList::new();
+        offsets
+            .add_partition_offset(
+                &position.topic,
+                position.partition,
+                Offset::Offset(position.offset + 1),
+            )
+            .map_err(|e| e.to_string())?;
+        self.consumer
+            .commit(&offsets, CommitMode::Async)
+            .map_err(|e| e.to_string())
+    }
+}
@@ -0,0 +1,77 @@
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+
+use std::sync::{Arc, Mutex};
+
+use super::{Position, Record, Source};
+
+/// 已提交的offset
+#[derive(Clone, Default)]
+pub struct Committed(Arc<Mutex<Option<i64>>>);
+
+impl Committed {
+    pub fn get(&self) -> Option<i64> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// 进程内的topic,测试时代替消息总线,全部drop后`LocalSource`关闭
+pub struct LocalTopic {
+    topic: String,
+    tx: UnboundedSender<Record>,
+    next_offset: Arc<Mutex<i64>>,
+    committed: Committed,
+}
+
+impl LocalTopic {
+    pub fn publish(&self, payload: &[u8]) {
+        let mut next_offset = self.next_offset.lock().unwrap();
+        let _ = self.tx.unbounded_send(Record {
+            position: Position {
+                topic: self.topic.clone(),
+                partition: 0,
+                offset: *next_offset,
+            },
+            payload: payload.to_vec(),
+        });
+        *next_offset += 1;
+    }
+
+    pub fn committed(&self) -> Committed {
+        self.committed.clone()
+    }
+}
+
+pub struct LocalSource {
+    rx: UnboundedReceiver<Record>,
+    committed: Committed,
+}
+
+/// 只有一个partition的topic
+pub fn local(topic: &str) -> (LocalTopic, LocalSource) {
+    let (tx, rx) = unbounded();
+    let committed = Committed::default();
+    (
+        LocalTopic {
+            topic: topic.to_owned(),
+            tx,
+            next_offset: Arc::default(),
+            committed: committed.clone(),
+        },
+        LocalSource { rx, committed },
+    )
+}
+
+#[tonic::async_trait(?Send)]
+impl Source for LocalSource {
+    async fn recv(&mut self) -> Result<Option<Record>, String> {
+        Ok(self.rx.next().await)
+    }
+
+    async fn commit(&mut self, position: &Position) -> Result<(), String> {
+        *self.committed.0.lock().unwrap() = Some(position.offset);
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+//! 从消息总线的topic消费消息,和grpc`Active`一样入队.
+//! 消息格式和`POST /push`的body一样,入队成功后才提交offset
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(test)]
+mod local;
+
+use actix::Addr;
+use log::{info, warn};
+
+use std::{fmt, time::Duration};
+
+use crate::{
+    addr::{Classify, Receipt, Redis, Registry, Trial},
+    handler::PushMessage,
+};
+
+#[cfg(feature = "kafka")]
+pub use self::kafka::*;
+#[cfg(test)]
+pub use self::local::*;
+
+/// wait before retrying a record, doubled after each failure
+const INGEST_BACKOFF: Duration = Duration::from_secs(1);
+/// longest wait between retries of a record
+const INGEST_MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// attempts to queue a record before its failed receivers are dropped
+const INGEST_ATTEMPTS: usize = 8;
+
+/// 记录在topic里的位置
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]@{}", self.topic, self.partition, self.offset)
+    }
+}
+
+/// topic里的一条记录
+pub struct Record {
+    pub position: Position,
+    pub payload: Vec<u8>,
+}
+
+/// 消息来源
+#[tonic::async_trait(?Send)]
+pub trait Source {
+    /// 等待下一条记录,来源关闭时为`None`
+    async fn recv(&mut self) -> Result<Option<Record>, String>;
+    /// 记录已经入队,提交它的offset
+    async fn commit(&mut self, position: &Position) -> Result<(), String>;
+}
+
+/// 记录转换为`Trial`,生产者为topic名.
+/// 消息ID由记录的位置生成,重复消费的消息客户端可以去重
+fn trial(record: &Record) -> Result<Trial, String> {
+    let PushMessage {
+        tenant,
+        receivers,
+        mut message,
+        callback,
+    } = serde_json::from_slice(&record.payload).map_err(|e| e.to_string())?;
+    let Position {
+        topic,
+        partition,
+        offset,
+    } = &record.position;
+    message.stamp(topic);
+    message.message_id = format!("{}-{}-{}", topic, partition, offset);
+    message.callback = callback;
+    Ok(Trial {
+        tenant,
+        message,
+        receivers,
+    })
+}
+
+/// 暂时失败的接收者,永久失败的(如key的类型不对)记下日志后放弃
+fn retryable(receipts: Vec<Receipt>, position: &Position) -> Vec<String> {
+    let mut receivers = Vec::new();
+    for receipt in receipts {
+        match receipt.queued {
+            Ok(_) => {}
+            Err(_) if receipt.transient => receivers.push(receipt.receiver),
+            Err(e) => warn!("drop {} for {}: {}", position, receipt.receiver, e),
+        }
+    }
+    receivers
+}
+
+/// 重试暂时的失败,失败的接收者单独重试.
+/// 重试`INGEST_ATTEMPTS`次后仍然失败的接收者记下日志后放弃,offset照样提交
+async fn enqueue(redis_addr: &Addr<Redis>, mut trial: Trial, position: &Position) {
+    let mut backoff = INGEST_BACKOFF;
+    for attempt in 1..=INGEST_ATTEMPTS {
+        match redis_addr.send(trial.clone()).await {
+            Ok(Ok(receipts)) => {
+                let failed = retryable(receipts, position);
+                if failed.is_empty() {
+                    return;
+                }
+                warn!("{}: {} receivers are not queued", position, failed.len());
+                trial.receivers = failed;
+            }
+            Ok(Err(e)) => warn!("{}: {}", position, e),
+            Err(e) => warn!("{}: {}", position, e),
+        }
+        if attempt < INGEST_ATTEMPTS {
+            actix_web::rt::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(INGEST_MAX_BACKOFF);
+        }
+    }
+    warn!(
+        "drop {} for {}: not queued after {} attempts",
+        position,
+        trial.receivers.join(","),
+        INGEST_ATTEMPTS
+    );
+}
+
+/// 消费`source`直到它关闭.
+/// 格式错误或者注册表拒绝的记录无法重试,记下日志后跳过
+pub async fn ingest(
+    mut source: impl Source,
+    registry_addr: Addr<Registry>,
+    redis_addr: Addr<Redis>,
+) {
+    loop {
+        let record = match source.recv().await {
+            Ok(Some(record)) => record,
+            Ok(None) => {
+                info!("ingestion source is closed");
+                return;
+            }
+            Err(e) => {
+                warn!("ingestion source error: {}", e);
+                actix_web::rt::time::sleep(INGEST_BACKOFF).await;
+                continue;
+            }
+        };
+
+        let position = &record.position;
+        match trial(&record) {
+            Ok(trial) => match registry_addr
+                .send(Classify {
+                    trials: vec![trial],
+                })
+                .await
+            {
+                Ok(mut trials) => match trials.pop().expect("classify keeps every trial") {
+                    Ok(trial) => enqueue(&redis_addr, trial, position).await,
+                    Err(e) => warn!("skip {}: {}", position, e),
+                },
+                Err(e) => {
+                    // the registry is gone, veda is shutting down
+                    warn!("stop ingestion at {}: {}", position, e);
+                    return;
+                }
+            },
+            Err(e) => warn!("skip {}: {}", position, e),
+        }
+
+        // redelivered after a restart if the commit is lost
+        if let Err(e) = source.commit(position).await {
+            warn!("unable to commit {}: {}", position, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        addr::{Poll, Webhook},
+        entity::Meister,
+        storage::MemoryStorage,
+    };
+    use actix::Actor;
+    use std::sync::Arc;
+
+    #[actix_rt::test]
+    async fn ingest_a_topic() {
+        let (topic, source) = local("missions");
+        topic.publish(
+            br#"{"receivers":["setsuna"],"activity_type":"mission","activity":"trans-am"}"#,
+        );
+        topic.publish(b"not json");
+        topic.publish(
+            br#"{"tenant":"celestial","receivers":["lockon"],"activity_type":"mission","activity":"snipe"}"#,
+        );
+
+        let webhook = Webhook::new(None).start();
+        let storage = Arc::new(MemoryStorage::default());
+        let redis_addr = Redis::new(storage.clone(), webhook.recipient()).start();
+        let registry_addr = Registry::new(storage).start();
+        // the source is closed once every record is consumed
+        let committed = topic.committed();
+        drop(topic);
+        ingest(source, registry_addr, redis_addr.clone()).await;
+        assert_eq!(committed.get(), Some(2));
+
+        let activities = redis_addr
+            .send(Poll {
+                meister: Meister::new("celestial", "lockon"),
+                cursor: None,
+                count: 10,
+            })
+            .await
+            .unwrap()
+            .unwrap()
+            .activities;
+        assert_eq!(activities.len(), 1);
+        assert_eq!(activities[0].message_id, "missions-0-2");
+        assert_eq!(activities[0].producer, "missions");
+    }
+
+    #[test]
+    fn retry_the_transient_failures() {
+        let receipt = |receiver: &str, queued: Result<&str, &str>, transient| Receipt {
+            receiver: receiver.to_owned(),
+            queued: queued.map(str::to_owned).map_err(str::to_owned),
+            transient,
+            devices: 0,
+        };
+        let position = Position {
+            topic: "missions".to_owned(),
+            partition: 0,
+            offset: 0,
+        };
+        let receivers = retryable(
+            vec![
+                receipt("setsuna", Ok("1-0"), false),
+                receipt("lockon", Err("broken pipe"), true),
+                receipt("allelujah", Err("WRONGTYPE"), false),
+            ],
+            &position,
+        );
+        assert_eq!(receivers, vec!["lockon"]);
+    }
+}
@@ -2,18 +2,30 @@
 extern crate lazy_static;
 
 mod addr;
+mod auth;
 pub mod activity {
     tonic::include_proto!("activity");
 }
 
 mod config;
 mod constants;
+mod eio;
 mod entity;
 mod handler;
+#[cfg(any(feature = "kafka", test))]
+mod ingest;
 mod server;
+mod storage;
+mod tls;
+use config::{Opts, CONFIG};
 use server::serv;
+use structopt::StructOpt;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    if Opts::from_args().print_config {
+        print!("{}", CONFIG.to_toml());
+        return Ok(());
+    }
     serv().await
 }
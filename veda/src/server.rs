@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 
 use actix::Actor;
 
@@ -7,43 +7,135 @@ use actix_web::{
     web::{self, Data},
     App, HttpServer,
 };
-use tonic::transport::Server;
+use log::warn;
+use tonic::transport::{Server, ServerTlsConfig};
 
+#[cfg(feature = "kafka")]
+use crate::ingest::{ingest, KafkaSource};
 use crate::{
     activity::activity_source_server::ActivitySourceServer,
-    addr::{add_websocket, init_redis, Seravee},
+    addr::{init_redis, Limit, Limiter, Registry, Seravee, Webhook, Websocket},
+    auth::{Authenticator, Producers},
     config::CONFIG,
-    handler::socket_route,
+    handler::{
+        broadcast_route, kick_route, list_kinds_route, list_sessions_route, online_route,
+        poll_route, push_msg_route, register_kind_route, socket_io_route, socket_route, sse_route,
+        unregister_kind_route,
+    },
+    storage,
+    tls::{self, CertResolver},
 };
 
 pub async fn serv() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", &CONFIG.log);
     env_logger::init();
-    let redis_addr = init_redis(&CONFIG.redis_url);
+    let webhook_addr = Webhook::new(CONFIG.webhook_secret.clone()).start();
+    let storage = storage::open(&CONFIG).expect("unable to open storage");
+    let redis_addr = init_redis(storage.clone(), webhook_addr.recipient());
     let addr: SocketAddr = CONFIG.grpc_url.parse().unwrap();
 
+    let limiter_addr = Limiter::new(
+        Limit {
+            rate: CONFIG.producer_rate,
+            burst: CONFIG.producer_burst,
+        },
+        Limit {
+            rate: CONFIG.receiver_rate,
+            burst: CONFIG.receiver_burst,
+        },
+    )
+    .start();
+
+    let registry = match &CONFIG.activity_types {
+        Some(path) => Registry::load(path, storage).expect("unable to load activity types"),
+        None => Registry::new(storage),
+    };
+    let registry_addr = registry.start();
+    // every worker shares one registry of sessions
+    let websocket_addr = Websocket::default().start();
+
+    #[cfg(feature = "kafka")]
+    if let (Some(brokers), Some(topic)) = (&CONFIG.ingest_brokers, &CONFIG.ingest_topic) {
+        let source = KafkaSource::new(brokers, topic, &CONFIG.ingest_group)
+            .expect("unable to consume kafka");
+        actix_web::rt::spawn(ingest(source, registry_addr.clone(), redis_addr.clone()));
+    }
+
     let seravee = Seravee {
-        addr: addr,
-        redis_addr: redis_addr.clone().recipient(),
+        redis_addr: redis_addr.clone(),
+        limiter_addr: limiter_addr.clone(),
+        registry_addr: registry_addr.clone(),
+        websocket_addr: websocket_addr.clone(),
+    };
+
+    let resolver = match (&CONFIG.tls_cert, &CONFIG.tls_key) {
+        (Some(cert), Some(key)) => {
+            let resolver = CertResolver::new(cert, key).expect("unable to load tls certificate");
+            resolver.watch();
+            Some(resolver)
+        }
+        _ => None,
     };
 
-    let seravee_addr = seravee.clone().start();
+    let mut grpc = Server::builder();
+    if let Some(resolver) = &resolver {
+        let mut config = tls::server_config(resolver.clone(), CONFIG.grpc_client_ca.as_deref())
+            .expect("unable to load grpc client ca");
+        config.set_protocols(&[b"h2".to_vec()]);
+        grpc = grpc
+            .tls_config(ServerTlsConfig::new().rustls_server_config(config).clone())
+            .expect("unable to configure grpc tls");
+    }
+
+    let producers = match &CONFIG.producers {
+        Some(path) => Some(Arc::new(
+            Producers::load(path).expect("unable to load producers"),
+        )),
+        None => {
+            warn!("producers are not authenticated, set `producers` to enable it");
+            None
+        }
+    };
+    let authenticator = Authenticator { producers };
+
+    let interceptor = authenticator.clone();
     actix_web::rt::spawn(async move {
-        let _ = Server::builder()
-            .add_service(ActivitySourceServer::new(seravee))
+        let _ = grpc
+            .add_service(ActivitySourceServer::with_interceptor(seravee, interceptor))
             .serve(addr)
             .await;
     });
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
-            .configure(add_websocket)
             .app_data(Data::new(redis_addr.clone()))
-            .app_data(Data::new(seravee_addr.clone()))
+            .app_data(Data::new(limiter_addr.clone()))
+            .app_data(Data::new(registry_addr.clone()))
+            .app_data(Data::new(websocket_addr.clone()))
+            .app_data(Data::new(authenticator.clone()))
             .service(web::resource("/ws/").to(socket_route))
-    })
-    .bind(&CONFIG.server)?
-    .run()
-    .await
+            .service(web::resource("/socket.io/").to(socket_io_route))
+            .service(web::resource("/sse").route(web::get().to(sse_route)))
+            .service(web::resource("/poll").route(web::get().to(poll_route)))
+            .service(web::resource("/push").route(web::post().to(push_msg_route)))
+            .service(web::resource("/broadcast").route(web::post().to(broadcast_route)))
+            .service(web::resource("/online").route(web::get().to(online_route)))
+            .service(web::resource("/admin/activity-types").route(web::get().to(list_kinds_route)))
+            .service(
+                web::resource("/admin/activity-types/{activity_type}")
+                    .route(web::put().to(register_kind_route))
+                    .route(web::delete().to(unregister_kind_route)),
+            )
+            .service(web::resource("/admin/sessions").route(web::get().to(list_sessions_route)))
+            .service(web::resource("/admin/kick").route(web::post().to(kick_route)))
+    });
+    let server = match resolver {
+        Some(resolver) => server.bind_rustls(
+            &CONFIG.server,
+            tls::server_config(resolver, None).expect("unable to configure tls"),
+        )?,
+        None => server.bind(&CONFIG.server)?,
+    };
+    server.run().await
 }
@@ -0,0 +1,196 @@
+use log::warn;
+use redis::{
+    cluster::{cluster_pipe, ClusterClient, ClusterConnection},
+    Client, Cmd, Connection, ConnectionAddr, ConnectionInfo, ConnectionLike, ErrorKind,
+    FromRedisValue, IntoConnectionInfo, RedisError, RedisResult, Value,
+};
+
+use crate::config::RedisMode;
+
+/// 按部署方式建立redis连接
+pub enum Connector {
+    Single(Client),
+    /// 第一个种子节点用于订阅,cluster的PUBLISH会广播到所有节点
+    Cluster(ClusterClient, Client),
+    /// 每次连接都向sentinel查询当前的master,master使用第一个sentinel地址里的db和密码
+    Sentinel {
+        sentinels: Vec<Client>,
+        master: String,
+        info: ConnectionInfo,
+    },
+}
+
+impl Connector {
+    /// `urls`为逗号分隔的地址,cluster是种子节点,sentinel是sentinel节点
+    pub fn open(mode: RedisMode, urls: &str, master: Option<&str>) -> RedisResult<Self> {
+        let urls: Vec<&str> = urls
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .collect();
+        let first = *urls
+            .first()
+            .ok_or((ErrorKind::InvalidClientConfig, "redis_url is empty"))?;
+        match mode {
+            RedisMode::Single => Ok(Connector::Single(Client::open(first)?)),
+            RedisMode::Cluster => Ok(Connector::Cluster(
+                ClusterClient::open(urls.clone())?,
+                Client::open(first)?,
+            )),
+            RedisMode::Sentinel => Ok(Connector::Sentinel {
+                sentinels: urls
+                    .iter()
+                    .map(|url| Client::open(*url))
+                    .collect::<RedisResult<_>>()?,
+                master: master
+                    .ok_or((ErrorKind::InvalidClientConfig, "redis_master is required"))?
+                    .to_owned(),
+                info: first.into_connection_info()?,
+            }),
+        }
+    }
+
+    /// cluster模式下同一个用户的key用hash tag放到同一个slot
+    pub fn hash_tags(&self) -> bool {
+        matches!(self, Connector::Cluster(..))
+    }
+
+    pub fn get_connection(&self) -> RedisResult<RedisConnection> {
+        match self {
+            Connector::Single(cli) => cli.get_connection().map(RedisConnection::Single),
+            Connector::Cluster(cli, _) => cli.get_connection().map(RedisConnection::Cluster),
+            Connector::Sentinel { .. } => self.pubsub_connection().map(RedisConnection::Single),
+        }
+    }
+
+    /// 用于SUBSCRIBE的单节点连接
+    pub fn pubsub_connection(&self) -> RedisResult<Connection> {
+        match self {
+            Connector::Single(cli) | Connector::Cluster(_, cli) => cli.get_connection(),
+            Connector::Sentinel {
+                sentinels,
+                master,
+                info,
+            } => {
+                let mut error = None;
+                for sentinel in sentinels {
+                    match connect_master(sentinel, master, info) {
+                        Ok(con) => return Ok(con),
+                        Err(e) => {
+                            warn!("sentinel can't find master `{}`: {}", master, e);
+                            error = Some(e);
+                        }
+                    }
+                }
+                Err(error.unwrap_or_else(|| {
+                    (ErrorKind::InvalidClientConfig, "no sentinel configured").into()
+                }))
+            }
+        }
+    }
+}
+
+/// 向sentinel查询master的地址并连接,确认它没有被降级为replica
+fn connect_master(
+    sentinel: &Client,
+    master: &str,
+    info: &ConnectionInfo,
+) -> RedisResult<Connection> {
+    let mut con = sentinel.get_connection()?;
+    let (host, port): (String, u16) = redis::cmd("SENTINEL")
+        .arg("get-master-addr-by-name")
+        .arg(master)
+        .query(&mut con)?;
+
+    let mut con = Client::open(ConnectionInfo {
+        addr: ConnectionAddr::Tcp(host, port),
+        redis: info.redis.clone(),
+    })?
+    .get_connection()?;
+    let role: Vec<Value> = redis::cmd("ROLE").query(&mut con)?;
+    match role.first() {
+        Some(Value::Data(role)) if role == b"master" => Ok(con),
+        _ => Err((ErrorKind::ReadOnly, "sentinel returned a replica").into()),
+    }
+}
+
+/// 连接断开,或者failover后master变成只读时,需要重新连接
+pub fn is_broken(e: &RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.kind() == ErrorKind::ReadOnly
+}
+
+/// 单机(包括sentinel找到的master)或者cluster连接
+pub enum RedisConnection {
+    Single(Connection),
+    Cluster(ClusterConnection),
+}
+
+impl RedisConnection {
+    /// 批量执行命令,cluster按节点拆分pipeline
+    pub fn pipeline<T: FromRedisValue>(&mut self, cmds: Vec<Cmd>) -> RedisResult<T> {
+        match self {
+            RedisConnection::Single(con) => {
+                let mut pipe = redis::pipe();
+                for cmd in cmds {
+                    pipe.add_command(cmd);
+                }
+                pipe.query(con)
+            }
+            RedisConnection::Cluster(con) => {
+                let mut pipe = cluster_pipe();
+                for cmd in cmds {
+                    pipe.add_command(cmd);
+                }
+                pipe.query(con)
+            }
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn ConnectionLike {
+        match self {
+            RedisConnection::Single(con) => con,
+            RedisConnection::Cluster(con) => con,
+        }
+    }
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        self.inner().req_packed_command(cmd)
+    }
+
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        self.inner().req_packed_commands(cmd, offset, count)
+    }
+
+    fn req_command(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        self.inner().req_command(cmd)
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            RedisConnection::Single(con) => con.get_db(),
+            RedisConnection::Cluster(con) => con.get_db(),
+        }
+    }
+
+    fn supports_pipelining(&self) -> bool {
+        matches!(self, RedisConnection::Single(_))
+    }
+
+    fn check_connection(&mut self) -> bool {
+        self.inner().check_connection()
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            RedisConnection::Single(con) => con.is_open(),
+            RedisConnection::Cluster(con) => con.is_open(),
+        }
+    }
+}
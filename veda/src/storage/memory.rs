@@ -0,0 +1,364 @@
+use chrono::Utc;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Mutex,
+};
+
+use super::{Storage, StorageResult};
+use crate::{
+    addr::{Receipt, Trial},
+    constants::CALLBACK_TTL,
+    entity::{Activity, Meister, Platform, SessionId, Subscription},
+};
+
+/// 和redis stream一样的`<毫秒>-<序号>`,`0`在所有消息之前
+type StreamId = (u64, u64);
+
+/// 清理过期回调的间隔,毫秒
+const CALLBACK_SWEEP_MILLIS: i64 = 60_000;
+
+fn parse_id(id: &str) -> Option<StreamId> {
+    match id.split_once('-') {
+        Some((ms, seq)) => Some((ms.parse().ok()?, seq.parse().ok()?)),
+        None => Some((id.parse().ok()?, 0)),
+    }
+}
+
+fn format_id((ms, seq): StreamId) -> String {
+    format!("{}-{}", ms, seq)
+}
+
+#[derive(Default)]
+struct Inner {
+    /// 租户的在线session和用户名
+    online_users: HashMap<String, HashMap<SessionId, String>>,
+    /// 用户的在线session
+    sessions: HashMap<Meister, HashSet<SessionId>>,
+    /// 在线session的设备
+    platforms: HashMap<SessionId, Platform>,
+    webhooks: HashMap<String, String>,
+    /// admin接口登记的消息类型
+    activity_kinds: HashMap<String, String>,
+    /// 接收者的消息队列
+    streams: HashMap<Meister, BTreeMap<StreamId, Activity>>,
+    /// 最后分配的消息ID
+    last_id: StreamId,
+    /// 回调和过期时间(毫秒)
+    callbacks: HashMap<(Meister, String), (Subscription, i64)>,
+    /// 下次清理过期回调的时间(毫秒)
+    next_sweep: i64,
+}
+
+impl Inner {
+    /// 新消息ID,同一毫秒内递增序号
+    fn next_id(&mut self) -> StreamId {
+        let now = Utc::now().timestamp_millis() as u64;
+        self.last_id = if now > self.last_id.0 {
+            (now, 0)
+        } else {
+            (self.last_id.0, self.last_id.1 + 1)
+        };
+        self.last_id
+    }
+}
+
+/// 消息存在进程内,重启后丢失,只适合测试和单机部署
+#[derive(Default)]
+pub struct MemoryStorage {
+    inner: Mutex<Inner>,
+}
+
+impl Storage for MemoryStorage {
+    fn online(&self, id: SessionId, meister: &Meister) -> StorageResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .online_users
+            .entry(meister.tenant.clone())
+            .or_default()
+            .insert(id, meister.username.clone());
+        inner
+            .sessions
+            .entry(meister.clone())
+            .or_default()
+            .insert(id);
+        Ok(())
+    }
+
+    fn platform(&self, id: SessionId, _: &Meister, platform: Platform) -> StorageResult<()> {
+        self.inner.lock().unwrap().platforms.insert(id, platform);
+        Ok(())
+    }
+
+    fn offline(&self, id: SessionId, meister: &Meister) -> StorageResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(users) = inner.online_users.get_mut(&meister.tenant) {
+            users.remove(&id);
+        }
+        if let Some(sessions) = inner.sessions.get_mut(meister) {
+            sessions.remove(&id);
+            if sessions.is_empty() {
+                inner.sessions.remove(meister);
+            }
+        }
+        inner.platforms.remove(&id);
+        Ok(())
+    }
+
+    fn webhook(&self, activity_type: &str) -> StorageResult<Option<String>> {
+        Ok(self
+            .inner
+            .lock()
+            .unwrap()
+            .webhooks
+            .get(activity_type)
+            .cloned())
+    }
+
+    fn subscribe(&self, activity_type: &str, url: Option<&str>) -> StorageResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        match url {
+            Some(url) => inner
+                .webhooks
+                .insert(activity_type.to_owned(), url.to_owned()),
+            None => inner.webhooks.remove(activity_type),
+        };
+        Ok(())
+    }
+
+    fn activity_kinds(&self) -> StorageResult<Vec<String>> {
+        let inner = self.inner.lock().unwrap();
+        Ok(inner.activity_kinds.values().cloned().collect())
+    }
+
+    fn register_kind(&self, activity_type: &str, kind: &str) -> StorageResult<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .activity_kinds
+            .insert(activity_type.to_owned(), kind.to_owned());
+        Ok(())
+    }
+
+    fn unregister_kind(&self, activity_type: &str) -> StorageResult<bool> {
+        let mut inner = self.inner.lock().unwrap();
+        Ok(inner.activity_kinds.remove(activity_type).is_some())
+    }
+
+    fn enqueue(&self, trials: &[Trial]) -> StorageResult<Vec<Vec<Receipt>>> {
+        let mut inner = self.inner.lock().unwrap();
+        Ok(trials
+            .iter()
+            .map(|trial| {
+                trial
+                    .receivers()
+                    .map(|meister| {
+                        let id = inner.next_id();
+                        let activity = Activity {
+                            id: Some(format_id(id)),
+                            ..trial.message.clone()
+                        };
+                        let devices = inner.sessions.get(&meister).map_or(0, HashSet::len);
+                        let receipt = Receipt {
+                            receiver: meister.username.clone(),
+                            queued: Ok(format_id(id)),
+                            transient: false,
+                            devices,
+                        };
+                        inner
+                            .streams
+                            .entry(meister)
+                            .or_default()
+                            .insert(id, activity);
+                        receipt
+                    })
+                    .collect()
+            })
+            .collect())
+    }
+
+    fn read_pending(
+        &self,
+        meister: &Meister,
+        cursor: &str,
+        count: usize,
+    ) -> StorageResult<Vec<Activity>> {
+        let cursor = parse_id(cursor).unwrap_or_default();
+        let inner = self.inner.lock().unwrap();
+        Ok(inner
+            .streams
+            .get(meister)
+            .map(|stream| {
+                stream
+                    .range(cursor..)
+                    .filter(|(id, _)| **id > cursor)
+                    .take(count)
+                    .map(|(_, activity)| activity.clone())
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    fn ack(&self, meister: &Meister, ids: &[String]) -> StorageResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(stream) = inner.streams.get_mut(meister) {
+            for id in ids.iter().filter_map(|id| parse_id(id)) {
+                stream.remove(&id);
+            }
+            if stream.is_empty() {
+                inner.streams.remove(meister);
+            }
+        }
+        Ok(())
+    }
+
+    fn ack_until(
+        &self,
+        meister: &Meister,
+        cursor: &str,
+        received: &mut dyn FnMut(&[Activity]),
+    ) -> StorageResult<()> {
+        let cursor = match parse_id(cursor) {
+            Some(cursor) => cursor,
+            None => return Ok(()),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let stream = match inner.streams.get_mut(meister) {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+        let rest = stream.split_off(&(cursor.0, cursor.1.saturating_add(1)));
+        let acked = std::mem::replace(stream, rest);
+        if stream.is_empty() {
+            inner.streams.remove(meister);
+        }
+        drop(inner);
+        received(&acked.into_values().collect::<Vec<_>>());
+        Ok(())
+    }
+
+    fn remember_callback(
+        &self,
+        meister: &Meister,
+        id: &str,
+        subscription: &Subscription,
+    ) -> StorageResult<()> {
+        let now = Utc::now().timestamp_millis();
+        let mut inner = self.inner.lock().unwrap();
+        // 没有被读取的回调不会在`callback`里删除,定期清理
+        if now >= inner.next_sweep {
+            inner.callbacks.retain(|_, (_, expire_at)| *expire_at > now);
+            inner.next_sweep = now + CALLBACK_SWEEP_MILLIS;
+        }
+        inner.callbacks.insert(
+            (meister.clone(), id.to_owned()),
+            (subscription.clone(), now + CALLBACK_TTL as i64 * 1000),
+        );
+        Ok(())
+    }
+
+    fn callback(&self, meister: &Meister, id: &str) -> StorageResult<Option<Subscription>> {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (meister.clone(), id.to_owned());
+        match inner.callbacks.get(&key) {
+            Some((_, expire_at)) if *expire_at <= Utc::now().timestamp_millis() => {
+                inner.callbacks.remove(&key);
+                Ok(None)
+            }
+            callback => Ok(callback.map(|(subscription, _)| subscription.clone())),
+        }
+    }
+
+    fn forget_callback(&self, meister: &Meister, id: &str) -> StorageResult<()> {
+        self.inner
+            .lock()
+            .unwrap()
+            .callbacks
+            .remove(&(meister.clone(), id.to_owned()));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_some_activities() {
+        let storage = MemoryStorage::default();
+        let setsuna = Meister::new("celestial", "setsuna");
+        storage.online(SessionId::new_v4(), &setsuna).unwrap();
+
+        let trial = Trial {
+            tenant: "celestial".to_owned(),
+            message: Activity {
+                activity_type: "mission".to_owned(),
+                ..Default::default()
+            },
+            receivers: vec!["setsuna".to_owned(), "lockon".to_owned()],
+        };
+        let receipts = storage.enqueue(&[trial]).unwrap().pop().unwrap();
+        assert_eq!(receipts[0].devices, 1);
+        assert_eq!(receipts[1].devices, 0);
+
+        let first = receipts[0].queued.clone().unwrap();
+        let trial = Trial {
+            tenant: "celestial".to_owned(),
+            message: Activity {
+                activity_type: "mission".to_owned(),
+                ..Default::default()
+            },
+            receivers: vec!["setsuna".to_owned()],
+        };
+        let second = storage.enqueue(&[trial]).unwrap()[0][0]
+            .queued
+            .clone()
+            .unwrap();
+
+        let pending = storage.read_pending(&setsuna, "0", 10).unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0].id.as_deref(), Some(first.as_str()));
+        assert_eq!(storage.read_pending(&setsuna, &first, 10).unwrap().len(), 1);
+
+        let mut received = Vec::new();
+        storage
+            .ack_until(&setsuna, &first, &mut |page| {
+                received.extend_from_slice(page)
+            })
+            .unwrap();
+        assert_eq!(received.len(), 1);
+        storage.ack(&setsuna, &[second]).unwrap();
+        assert!(storage.read_pending(&setsuna, "0", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn sweep_the_expired_callbacks() {
+        let storage = MemoryStorage::default();
+        let setsuna = Meister::new("celestial", "setsuna");
+        let subscription = Subscription {
+            url: "http://127.0.0.1:8000/receipts".to_owned(),
+            activity_type: "mission".to_owned(),
+            message_id: "gn-001".to_owned(),
+        };
+        storage
+            .remember_callback(&setsuna, "1-0", &subscription)
+            .unwrap();
+        // never looked up after it expires
+        {
+            let mut inner = storage.inner.lock().unwrap();
+            inner
+                .callbacks
+                .values_mut()
+                .for_each(|(_, expire_at)| *expire_at = 0);
+            inner.next_sweep = 0;
+        }
+        storage
+            .remember_callback(&setsuna, "2-0", &subscription)
+            .unwrap();
+        let inner = storage.inner.lock().unwrap();
+        assert_eq!(inner.callbacks.len(), 1);
+        assert!(inner
+            .callbacks
+            .contains_key(&(setsuna.clone(), "2-0".to_owned())));
+    }
+}
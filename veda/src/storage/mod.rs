@@ -0,0 +1,108 @@
+//! 消息队列、在线状态和设备的存储,redis用于生产环境,内存用于测试和单机部署
+mod connector;
+mod memory;
+mod redis;
+
+use std::{fmt, sync::Arc};
+
+use crate::{
+    addr::{Receipt, Trial},
+    config::{Config, StorageKind},
+    entity::{Activity, Meister, Platform, SessionId, Subscription},
+};
+
+pub use self::{connector::*, memory::*, redis::*};
+
+/// 存储不可用,如redis连接失败
+#[derive(Debug)]
+pub struct StorageError(pub String);
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl From<::redis::RedisError> for StorageError {
+    fn from(e: ::redis::RedisError) -> Self {
+        StorageError(e.to_string())
+    }
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+/// 每个接收者一个消息队列,消息ID为`<毫秒>-<序号>`,和redis stream一样递增
+pub trait Storage: Send + Sync {
+    /// session上线,登记到租户的在线用户和用户的session
+    fn online(&self, id: SessionId, meister: &Meister) -> StorageResult<()>;
+    /// session登记的设备
+    fn platform(&self, id: SessionId, meister: &Meister, platform: Platform) -> StorageResult<()>;
+    /// session下线,同时清除它的设备
+    fn offline(&self, id: SessionId, meister: &Meister) -> StorageResult<()>;
+
+    /// 按消息类型登记的webhook
+    fn webhook(&self, activity_type: &str) -> StorageResult<Option<String>>;
+    /// `url`为`None`时取消
+    fn subscribe(&self, activity_type: &str, url: Option<&str>) -> StorageResult<()>;
+
+    /// admin接口登记的消息类型,json格式,所有实例共用
+    fn activity_kinds(&self) -> StorageResult<Vec<String>>;
+    fn register_kind(&self, activity_type: &str, kind: &str) -> StorageResult<()>;
+    /// 返回是否存在
+    fn unregister_kind(&self, activity_type: &str) -> StorageResult<bool>;
+
+    /// 按顺序把每个`Trial`写入接收者的队列,返回每个接收者的结果
+    fn enqueue(&self, trials: &[Trial]) -> StorageResult<Vec<Vec<Receipt>>>;
+    /// `cursor`之后最多`count`条消息,按入队顺序,包括已经过期的
+    fn read_pending(
+        &self,
+        meister: &Meister,
+        cursor: &str,
+        count: usize,
+    ) -> StorageResult<Vec<Activity>>;
+    /// 删除已推送或已过期的消息
+    fn ack(&self, meister: &Meister, ids: &[String]) -> StorageResult<()>;
+    /// 删除`cursor`(含)之前的消息,删除的消息分批交给`received`
+    fn ack_until(
+        &self,
+        meister: &Meister,
+        cursor: &str,
+        received: &mut dyn FnMut(&[Activity]),
+    ) -> StorageResult<()>;
+
+    /// 记下已推送消息的回调,等待阅读和确认
+    fn remember_callback(
+        &self,
+        meister: &Meister,
+        id: &str,
+        subscription: &Subscription,
+    ) -> StorageResult<()>;
+    fn callback(&self, meister: &Meister, id: &str) -> StorageResult<Option<Subscription>>;
+    /// 确认后不再有回执
+    fn forget_callback(&self, meister: &Meister, id: &str) -> StorageResult<()>;
+
+    /// 通知其它veda实例这些用户有新消息,只有一个实例的存储不需要
+    fn publish_wake(&self, _meisters: &[Meister]) -> StorageResult<()> {
+        Ok(())
+    }
+    /// 接收其它实例的通知,订阅断开时返回错误,不支持时直接返回
+    fn subscribe_wake(&self, _wake: &mut dyn FnMut(Meister)) -> StorageResult<()> {
+        Ok(())
+    }
+}
+
+/// 按配置打开存储
+pub fn open(config: &Config) -> Result<Arc<dyn Storage>, String> {
+    match config.storage {
+        StorageKind::Redis => {
+            let connector = Connector::open(
+                config.redis_mode,
+                &config.redis_url,
+                config.redis_master.as_deref(),
+            )
+            .map_err(|e| format!("unable to connect to redis {}: {}", &config.redis_url, e))?;
+            Ok(Arc::new(RedisStorage::new(connector)))
+        }
+        StorageKind::Memory => Ok(Arc::new(MemoryStorage::default())),
+    }
+}
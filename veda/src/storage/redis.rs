@@ -0,0 +1,417 @@
+use log::warn;
+use redis::{
+    streams::{StreamId, StreamKey, StreamRangeReply, StreamReadOptions, StreamReadReply},
+    Cmd, Commands, RedisResult,
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use std::sync::Mutex;
+
+use super::{is_broken, Connector, RedisConnection, Storage, StorageError, StorageResult};
+use crate::{
+    addr::{Receipt, Trial},
+    constants::{CALLBACK_TTL, PIPELINE_SIZE},
+    entity::{Activity, ContentType, Meister, Platform, SessionId, Subscription},
+};
+
+/// redis key,全局用户(租户为空)沿用没有租户时的格式.
+/// cluster模式下用户部分是hash tag,同一个用户的key在同一个slot,`TRIAL_SCRIPT`才能同时访问
+fn tenanted(prefix: &str, meister: &Meister, hash_tags: bool) -> String {
+    let user = if meister.tenant.is_empty() {
+        meister.username.clone()
+    } else {
+        format!("{}:{}", meister.tenant, meister.username)
+    };
+    if hash_tags {
+        format!("{}:{{{}}}", prefix, user)
+    } else {
+        format!("{}:{}", prefix, user)
+    }
+}
+
+/// 租户的在线用户hset
+fn hset_online_users(tenant: &str) -> String {
+    if tenant.is_empty() {
+        "online-users".to_string()
+    } else {
+        format!("online-users:{}", tenant)
+    }
+}
+
+/// 按消息类型登记的webhook hset
+const HSET_WEBHOOKS: &str = "webhooks";
+
+/// admin接口登记的消息类型hset
+const HSET_ACTIVITY_KINDS: &str = "activity-kinds";
+
+/// 新消息通知的频道
+const CHANNEL_WAKE: &str = "veda-wake";
+
+/// 新消息通知,忽略自己发出的
+#[derive(Deserialize, Serialize)]
+struct Wake {
+    origin: Uuid,
+    meisters: Vec<Meister>,
+}
+
+/// XADD an activity and count the receiver's sessions in one round trip.
+/// `redis.pcall` keeps an error (e.g. WRONGTYPE) from failing the whole pipeline,
+/// so every receiver gets its own result.
+const TRIAL_SCRIPT: &str = r#"
+local id = redis.pcall('XADD', KEYS[1], '*', unpack(ARGV))
+local devices = redis.pcall('SCARD', KEYS[2])
+if type(devices) ~= 'number' then devices = 0 end
+if type(id) == 'table' then return {0, id.err, devices} end
+return {1, id, devices}
+"#;
+
+/// stream里的消息
+fn activity(t: &StreamId) -> Activity {
+    let headers: Option<String> = t.get("headers");
+    let content_type: Option<String> = t.get("content_type");
+    Activity {
+        id: Some(t.id.clone()),
+        activity_type: t.get("activity_type").unwrap_or_default(),
+        content_type: content_type
+            .and_then(|content_type| ContentType::parse(&content_type))
+            .unwrap_or_default(),
+        activity: t.get("activity").unwrap_or_default(),
+        message_id: t.get("message_id").unwrap_or_default(),
+        created_at: t.get("created_at").unwrap_or_default(),
+        producer: t.get("producer").unwrap_or_default(),
+        expire_at: t.get("expire_at"),
+        priority: t.get("priority").unwrap_or_default(),
+        headers: headers
+            .and_then(|headers| serde_json::from_str(&headers).ok())
+            .unwrap_or_default(),
+        callback: t.get("callback"),
+        ..Default::default()
+    }
+}
+
+/// 消息存在接收者的redis stream里
+pub struct RedisStorage {
+    connector: Connector,
+    /// 这个实例发出的通知
+    origin: Uuid,
+    /// 空闲的连接,断开的连接不再放回来
+    idle: Mutex<Vec<RedisConnection>>,
+}
+
+impl RedisStorage {
+    pub fn new(connector: Connector) -> Self {
+        Self {
+            connector,
+            origin: Uuid::new_v4(),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// 用户的设备hset
+    fn key_platform(&self, meister: &Meister) -> String {
+        tenanted("platforms", meister, self.connector.hash_tags())
+    }
+    /// 消息队列
+    fn key_activity(&self, meister: &Meister) -> String {
+        tenanted("veda-activity", meister, self.connector.hash_tags())
+    }
+    /// 用户在线session的set
+    fn key_sessions(&self, meister: &Meister) -> String {
+        tenanted("sessions", meister, self.connector.hash_tags())
+    }
+    /// 已推送消息的回调hset,等待阅读和确认
+    fn key_callbacks(&self, meister: &Meister) -> String {
+        tenanted("callbacks", meister, self.connector.hash_tags())
+    }
+
+    /// 取一个空闲连接执行命令,failover或者连接断开后重新连接
+    fn with<T>(&self, f: impl FnOnce(&mut RedisConnection) -> RedisResult<T>) -> StorageResult<T> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut con = match idle {
+            Some(con) => con,
+            None => self.connector.get_connection()?,
+        };
+        let res = f(&mut con);
+        match &res {
+            Err(e) if is_broken(e) => warn!("drop a broken redis connection: {}", e),
+            _ => self.idle.lock().unwrap().push(con),
+        }
+        Ok(res?)
+    }
+}
+
+impl Storage for RedisStorage {
+    fn online(&self, id: SessionId, meister: &Meister) -> StorageResult<()> {
+        self.with(|con| {
+            let _: () = con.hset(
+                hset_online_users(&meister.tenant),
+                id.to_string(),
+                &meister.username,
+            )?;
+            con.sadd(self.key_sessions(meister), id.to_string())
+        })
+    }
+
+    fn platform(&self, id: SessionId, meister: &Meister, platform: Platform) -> StorageResult<()> {
+        self.with(|con| con.hset(self.key_platform(meister), id.to_string(), platform))
+    }
+
+    fn offline(&self, id: SessionId, meister: &Meister) -> StorageResult<()> {
+        let id = id.to_string();
+        self.with(|con| {
+            let _: () = con.hdel(hset_online_users(&meister.tenant), &id)?;
+            let _: () = con.srem(self.key_sessions(meister), &id)?;
+            con.hdel(self.key_platform(meister), &id)
+        })
+    }
+
+    fn webhook(&self, activity_type: &str) -> StorageResult<Option<String>> {
+        self.with(|con| con.hget(HSET_WEBHOOKS, activity_type))
+    }
+
+    fn subscribe(&self, activity_type: &str, url: Option<&str>) -> StorageResult<()> {
+        self.with(|con| match url {
+            Some(url) => con.hset(HSET_WEBHOOKS, activity_type, url),
+            None => con.hdel(HSET_WEBHOOKS, activity_type),
+        })
+    }
+
+    fn activity_kinds(&self) -> StorageResult<Vec<String>> {
+        self.with(|con| con.hvals(HSET_ACTIVITY_KINDS))
+    }
+
+    fn register_kind(&self, activity_type: &str, kind: &str) -> StorageResult<()> {
+        self.with(|con| con.hset(HSET_ACTIVITY_KINDS, activity_type, kind))
+    }
+
+    fn unregister_kind(&self, activity_type: &str) -> StorageResult<bool> {
+        self.with(|con| con.hdel(HSET_ACTIVITY_KINDS, activity_type))
+    }
+
+    fn enqueue(&self, trials: &[Trial]) -> StorageResult<Vec<Vec<Receipt>>> {
+        let mut receipts: Vec<Vec<Receipt>> = trials
+            .iter()
+            .map(|trial| Vec::with_capacity(trial.receivers.len()))
+            .collect();
+
+        // (index of trial, receiver) for every xadd
+        let commands: Vec<(usize, &String)> = trials
+            .iter()
+            .enumerate()
+            .flat_map(|(i, trial)| trial.receivers.iter().map(move |receiv| (i, receiv)))
+            .collect();
+
+        for chunk in commands.chunks(PIPELINE_SIZE) {
+            let cmds = chunk
+                .iter()
+                .map(|(i, receiv)| {
+                    let meister = Meister::new(&trials[*i].tenant, receiv);
+                    let mut cmd = Cmd::new();
+                    cmd.arg("EVAL")
+                        .arg(TRIAL_SCRIPT)
+                        .arg(2)
+                        .arg(self.key_activity(&meister))
+                        .arg(self.key_sessions(&meister))
+                        .arg(&trials[*i].message);
+                    cmd
+                })
+                .collect();
+
+            let replies: StorageResult<Vec<(bool, String, usize)>> =
+                self.with(|con| con.pipeline(cmds));
+            match replies {
+                Ok(replies) => {
+                    for ((i, receiv), (queued, id, devices)) in chunk.iter().zip(replies) {
+                        receipts[*i].push(Receipt {
+                            receiver: receiv.to_string(),
+                            queued: if queued { Ok(id) } else { Err(id) },
+                            transient: false,
+                            devices,
+                        });
+                    }
+                }
+                // the connection is broken, we can't tell which entries were written
+                Err(e) => {
+                    for (i, receiv) in chunk {
+                        receipts[*i].push(Receipt {
+                            receiver: receiv.to_string(),
+                            queued: Err(e.to_string()),
+                            transient: true,
+                            devices: 0,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(receipts)
+    }
+
+    fn read_pending(
+        &self,
+        meister: &Meister,
+        cursor: &str,
+        count: usize,
+    ) -> StorageResult<Vec<Activity>> {
+        let opts = StreamReadOptions::default().count(count);
+        let ssr: Option<StreamReadReply> =
+            self.with(|con| con.xread_options(&[self.key_activity(meister)], &[cursor], &opts))?;
+        Ok(ssr
+            .map(|ssr| ssr.keys)
+            .unwrap_or_default()
+            .iter()
+            .flat_map(|StreamKey { ids, .. }| ids.iter().map(activity))
+            .collect())
+    }
+
+    fn ack(&self, meister: &Meister, ids: &[String]) -> StorageResult<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        self.with(|con| con.xdel(self.key_activity(meister), ids))
+    }
+
+    /// 每次读取`PIPELINE_SIZE`条,删除后从头再读,长时间离线的积压不会一次读进内存
+    fn ack_until(
+        &self,
+        meister: &Meister,
+        cursor: &str,
+        received: &mut dyn FnMut(&[Activity]),
+    ) -> StorageResult<()> {
+        let stream_name = self.key_activity(meister);
+        loop {
+            let page: StreamRangeReply =
+                self.with(|con| con.xrange_count(&stream_name, "-", cursor, PIPELINE_SIZE))?;
+            let ids: Vec<String> = page.ids.iter().map(|t| t.id.clone()).collect();
+            self.ack(meister, &ids)?;
+            received(&page.ids.iter().map(activity).collect::<Vec<_>>());
+            if ids.len() < PIPELINE_SIZE {
+                return Ok(());
+            }
+        }
+    }
+
+    fn remember_callback(
+        &self,
+        meister: &Meister,
+        id: &str,
+        subscription: &Subscription,
+    ) -> StorageResult<()> {
+        let subscription =
+            serde_json::to_string(subscription).map_err(|e| StorageError(e.to_string()))?;
+        let key = self.key_callbacks(meister);
+        self.with(|con| {
+            let _: () = con.hset(&key, id, subscription)?;
+            con.expire(&key, CALLBACK_TTL)
+        })
+    }
+
+    fn callback(&self, meister: &Meister, id: &str) -> StorageResult<Option<Subscription>> {
+        let subscription: Option<String> =
+            self.with(|con| con.hget(self.key_callbacks(meister), id))?;
+        Ok(subscription.and_then(|subscription| serde_json::from_str(&subscription).ok()))
+    }
+
+    fn forget_callback(&self, meister: &Meister, id: &str) -> StorageResult<()> {
+        self.with(|con| con.hdel(self.key_callbacks(meister), id))
+    }
+
+    fn publish_wake(&self, meisters: &[Meister]) -> StorageResult<()> {
+        if meisters.is_empty() {
+            return Ok(());
+        }
+        let wake = serde_json::to_string(&Wake {
+            origin: self.origin,
+            meisters: meisters.to_vec(),
+        })
+        .map_err(|e| StorageError(e.to_string()))?;
+        self.with(|con| con.publish(CHANNEL_WAKE, wake))
+    }
+
+    fn subscribe_wake(&self, wake: &mut dyn FnMut(Meister)) -> StorageResult<()> {
+        let mut con = self.connector.pubsub_connection()?;
+        let mut pubsub = con.as_pubsub();
+        pubsub.subscribe(CHANNEL_WAKE)?;
+        loop {
+            let payload: String = pubsub.get_message()?.get_payload()?;
+            match serde_json::from_str::<Wake>(&payload) {
+                Ok(msg) if msg.origin != self.origin => {
+                    msg.meisters.into_iter().for_each(&mut *wake)
+                }
+                Ok(_) => (),
+                Err(e) => warn!("invalid wake-up {:?}: {}", payload, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::{ToRedisArgs, Value};
+
+    #[test]
+    fn tag_the_keys_of_a_user() {
+        let meister = Meister::new("celestial", "setsuna");
+        assert_eq!(
+            tenanted("sessions", &meister, false),
+            "sessions:celestial:setsuna"
+        );
+        assert_eq!(
+            tenanted("sessions", &meister, true),
+            "sessions:{celestial:setsuna}"
+        );
+        assert_eq!(
+            tenanted("sessions", &Meister::new("", "setsuna"), true),
+            "sessions:{setsuna}"
+        );
+    }
+
+    #[test]
+    fn carry_the_metadata() {
+        let mut message = Activity {
+            activity_type: "mission".to_owned(),
+            content_type: ContentType::Json,
+            activity: r#"{"target":"gn-x"}"#.to_owned(),
+            expire_at: Some(1626919030474),
+            priority: 7,
+            ..Default::default()
+        };
+        message.stamp("celestial-being");
+        message
+            .headers
+            .insert("trace-id".to_owned(), "0af7651916cd43dd".to_owned());
+
+        // the fields of an XADD, read back as a stream entry
+        let args = (&message).to_redis_args();
+        let entry = StreamId {
+            id: "1626919030474-0".to_owned(),
+            map: args
+                .chunks(2)
+                .map(|field| {
+                    (
+                        String::from_utf8(field[0].clone()).unwrap(),
+                        Value::Data(field[1].clone()),
+                    )
+                })
+                .collect(),
+        };
+        let queued = activity(&entry);
+        assert_eq!(queued.id.as_deref(), Some("1626919030474-0"));
+        assert_eq!(queued.message_id, message.message_id);
+        assert_eq!(queued.created_at, message.created_at);
+        assert_eq!(queued.producer, "celestial-being");
+        assert_eq!(queued.expire_at, message.expire_at);
+        assert_eq!(queued.priority, 7);
+        assert_eq!(queued.headers, message.headers);
+
+        let pushed = serde_json::to_value(&queued).unwrap();
+        assert_eq!(pushed["id"], "1626919030474-0");
+        assert_eq!(pushed["message_id"], message.message_id.as_str());
+        assert_eq!(pushed["producer"], "celestial-being");
+        assert_eq!(pushed["headers"]["trace-id"], "0af7651916cd43dd");
+        assert_eq!(pushed["activity"]["target"], "gn-x");
+    }
+}
@@ -0,0 +1,174 @@
+//! rustls配置,websocket和grpc共用同一个证书,证书文件改变后自动重新加载
+use log::{info, warn};
+use rustls::{
+    internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    sign::{self, CertifiedKey},
+    AllowAnyAnonymousOrAuthenticatedClient, ClientHello, NoClientAuth, ResolvesServerCert,
+    RootCertStore, ServerConfig,
+};
+
+use std::{
+    fs::File,
+    io::BufReader,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use crate::constants::TLS_RELOAD_INTERVAL;
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+/// 读取pem格式的证书链和私钥,私钥可以是pkcs8或rsa格式
+fn load(cert: &str, key: &str) -> Result<CertifiedKey, String> {
+    let chain = certs(&mut open(cert)?).map_err(|_| format!("{}: invalid certificate", cert))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificate found", cert));
+    }
+    let mut keys =
+        pkcs8_private_keys(&mut open(key)?).map_err(|_| format!("{}: invalid key", key))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(key)?).map_err(|_| format!("{}: invalid key", key))?;
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| format!("{}: no private key found", key))?;
+    let key = sign::any_supported_type(key).map_err(|_| "unsupported private key".to_owned())?;
+    Ok(CertifiedKey::new(chain, Arc::new(key)))
+}
+
+/// 证书和私钥最后修改的时间
+fn modified(cert: &str, key: &str) -> Option<SystemTime> {
+    let modified = |path: &str| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+    modified(cert).max(modified(key))
+}
+
+/// 每次握手使用最新加载的证书
+pub struct CertResolver {
+    cert: String,
+    key: String,
+    current: RwLock<(CertifiedKey, Option<SystemTime>)>,
+}
+
+impl CertResolver {
+    pub fn new(cert: &str, key: &str) -> Result<Arc<Self>, String> {
+        Ok(Arc::new(Self {
+            cert: cert.to_owned(),
+            key: key.to_owned(),
+            current: RwLock::new((load(cert, key)?, modified(cert, key))),
+        }))
+    }
+
+    /// 文件修改过就重新加载,加载失败时继续使用原来的证书
+    pub fn reload(&self) {
+        let modified = modified(&self.cert, &self.key);
+        if modified == self.current.read().unwrap().1 {
+            return;
+        }
+        match load(&self.cert, &self.key) {
+            Ok(certified) => {
+                info!("reloaded tls certificate {}", &self.cert);
+                *self.current.write().unwrap() = (certified, modified);
+            }
+            Err(e) => warn!("keep the old tls certificate: {}", e),
+        }
+    }
+
+    /// 定时检查证书文件
+    pub fn watch(self: &Arc<Self>) {
+        let resolver = self.clone();
+        actix_web::rt::spawn(async move {
+            loop {
+                actix_web::rt::time::sleep(TLS_RELOAD_INTERVAL).await;
+                resolver.reload();
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        Some(self.current.read().unwrap().0.clone())
+    }
+}
+
+/// `client_ca`不为空时校验客户端提供的证书,没有证书的客户端由`Authenticator`决定能否访问
+pub fn server_config(
+    resolver: Arc<CertResolver>,
+    client_ca: Option<&str>,
+) -> Result<ServerConfig, String> {
+    let mut config = match client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            match roots.add_pem_file(&mut open(path)?) {
+                Ok((valid, _)) if valid > 0 => (),
+                _ => return Err(format!("{}: no valid ca certificate found", path)),
+            }
+            ServerConfig::new(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+        }
+        None => ServerConfig::new(NoClientAuth::new()),
+    };
+    config.cert_resolver = resolver;
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::Path};
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// 覆盖文件,文件系统的时间精度可能分辨不出两次写入,所以忘掉加载时的修改时间
+    fn replace(resolver: &CertResolver, path: &Path, contents: &[u8]) {
+        fs::write(path, contents).unwrap();
+        resolver.current.write().unwrap().1 = None;
+    }
+
+    fn served(resolver: &CertResolver) -> Vec<u8> {
+        resolver.current.read().unwrap().0.cert[0].0.clone()
+    }
+
+    fn der(name: &str) -> Vec<u8> {
+        certs(&mut open(&fixture(name)).unwrap()).unwrap()[0]
+            .0
+            .clone()
+    }
+
+    #[test]
+    fn reload_a_certificate() {
+        let dir = std::env::temp_dir().join(format!("veda-tls-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::copy(fixture("exia.crt"), &cert).unwrap();
+        fs::copy(fixture("exia.key"), &key).unwrap();
+
+        let resolver = CertResolver::new(cert.to_str().unwrap(), key.to_str().unwrap()).unwrap();
+        assert_eq!(served(&resolver), der("exia.crt"));
+        resolver.reload();
+        assert_eq!(served(&resolver), der("exia.crt"));
+
+        replace(&resolver, &cert, &fs::read(fixture("dynames.crt")).unwrap());
+        replace(&resolver, &key, &fs::read(fixture("dynames.key")).unwrap());
+        resolver.reload();
+        assert_eq!(served(&resolver), der("dynames.crt"));
+
+        // a broken certificate keeps the old one in use
+        replace(&resolver, &cert, b"not a certificate");
+        resolver.reload();
+        assert_eq!(served(&resolver), der("dynames.crt"));
+
+        assert!(server_config(resolver.clone(), Some(&fixture("exia.crt"))).is_ok());
+        assert!(server_config(resolver, Some(&fixture("exia.key"))).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}